@@ -1,12 +1,23 @@
-use crate::memory::identity_map_pages;
-
 use super::{
   rsdp::{RsdpHeader, RsdtType},
   sdt::SdtHeader,
 };
+use crate::{memory, PHYS_MEM_OFFSET};
 
+use core::{mem::size_of, ptr, slice};
 use x86_64::structures::paging::PageTableFlags;
 
+// Far above the largest tables firmware ships, a length beyond it is garbage read from the wrong address.
+const MAX_TABLE_LENGTH: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcpiError {
+  Map,
+  Signature,
+  Length,
+  Checksum,
+}
+
 pub enum AcpiHeader {
   Rsdt(&'static SdtHeader, AcpiTableIterator),
   Xsdt(&'static SdtHeader, AcpiTableIterator),
@@ -39,37 +50,70 @@ impl AcpiTableIterator {
   }
 }
 
+// Every length from firmware goes through here before anything reads that far.
+pub fn map_table(phys: u64, length: usize) -> Result<u64, AcpiError> {
+  if length > MAX_TABLE_LENGTH {
+    return Err(AcpiError::Length);
+  }
+
+  let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+
+  match memory::map_physical_range(phys, length as u64, flags) {
+    Ok(virt) => Ok(virt.as_u64()),
+    Err(err) => {
+      log::warn!("failed to map acpi table at {:#x} ({:#x} bytes): {:?}", phys, length, err);
+
+      Err(AcpiError::Map)
+    }
+  }
+}
+
+pub fn physical_address(virt: u64) -> u64 {
+  virt - PHYS_MEM_OFFSET.get().expect("physical memory offset is unknown").as_u64()
+}
+
+pub fn checksum(addr: u64, length: usize) -> bool {
+  let bytes = unsafe { slice::from_raw_parts(addr as *const u8, length) };
+
+  bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
 impl Iterator for AcpiTableIterator {
   type Item = &'static SdtHeader;
 
   fn next(&mut self) -> Option<Self::Item> {
-    if self.current >= self.entries {
-      return None;
-    }
+    while self.current < self.entries {
+      // Entries are only 4 byte aligned, even the 8 byte ones of the xsdt.
+      let addr = match self.kind {
+        RsdtType::Rsdt => unsafe { ptr::read_unaligned((self.sdt.data_address() + self.current as u64 * 4) as *const u32) as u64 },
+        RsdtType::Xsdt => unsafe { ptr::read_unaligned((self.sdt.data_address() + self.current as u64 * 8) as *const u64) },
+      };
 
-    let entry = match self.kind {
-      RsdtType::Rsdt => self.sdt.data_address() + self.current as u64 * 4,
-      RsdtType::Xsdt => self.sdt.data_address() + self.current as u64 * 8,
-    };
+      self.current += 1;
 
-    self.current += 1;
+      match SdtHeader::from_addr(addr) {
+        Ok(sdt) => return Some(sdt),
+        Err(err) => log::warn!("skipping invalid acpi table at {:#x}: {:?}", addr, err),
+      }
+    }
 
-    Some(SdtHeader::from_addr(entry))
+    None
   }
 }
 
 impl AcpiHeader {
-  pub fn from_rsdp(rsdp: u64) -> Self {
-    if let Err(_) = identity_map_pages(rsdp, rsdp + 1, PageTableFlags::PRESENT, true) {
-      log::trace!("page with the rsdp was already identity mapped");
-    }
+  pub fn from_rsdp(rsdp: u64) -> Result<Self, AcpiError> {
+    // Only the extended checksum covers anything past the fixed size structure, it maps the rest itself.
+    let rsdp = unsafe { &*(map_table(rsdp, size_of::<RsdpHeader>())? as *const RsdpHeader) };
+
+    rsdp.validate()?;
 
-    let rsdp = unsafe { &*(rsdp as *const RsdpHeader) };
-    let sdt = SdtHeader::from_addr(rsdp.sdt_address());
+    let sdt = SdtHeader::from_addr(rsdp.sdt_address())?;
 
     match rsdp.sdt_type() {
-      RsdtType::Rsdt => AcpiHeader::Rsdt(sdt, AcpiTableIterator::from_rsdt(sdt)),
-      RsdtType::Xsdt => AcpiHeader::Xsdt(sdt, AcpiTableIterator::from_xsdt(sdt)),
+      RsdtType::Rsdt if sdt.signature() == "RSDT" => Ok(AcpiHeader::Rsdt(sdt, AcpiTableIterator::from_rsdt(sdt))),
+      RsdtType::Xsdt if sdt.signature() == "XSDT" => Ok(AcpiHeader::Xsdt(sdt, AcpiTableIterator::from_xsdt(sdt))),
+      _ => Err(AcpiError::Signature),
     }
   }
 }
@@ -5,7 +5,15 @@ mod sdt;
 use acpi::AcpiHeader;
 
 pub fn init(rsdp: u64) {
-  let header = AcpiHeader::from_rsdp(rsdp);
+  let header = match AcpiHeader::from_rsdp(rsdp) {
+    Ok(header) => header,
+    Err(err) => {
+      log::error!("ignoring acpi, the rsdp at {:#x} or its root table is invalid: {:?}", rsdp, err);
+
+      return;
+    }
+  };
+
   let (sdt, entries) = match header {
     AcpiHeader::Rsdt(sdt, entries) => (sdt, entries),
     AcpiHeader::Xsdt(sdt, entries) => (sdt, entries),
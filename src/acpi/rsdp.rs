@@ -1,3 +1,12 @@
+use super::acpi::{self, checksum, AcpiError};
+
+use core::mem::size_of;
+
+const SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+// The part of the structure ACPI 1.0 defines, covered by the first checksum.
+const V1_LENGTH: usize = 20;
+
 #[repr(C, packed)]
 pub struct RsdpHeader {
   signature: [u8; 8],
@@ -18,11 +27,42 @@ pub enum RsdtType {
 }
 
 impl RsdpHeader {
+  // Revisions before 2 only define the fields up to `rsdt_address`, the rest is neither meaningful nor checksummed.
+  pub fn validate(&self) -> Result<(), AcpiError> {
+    if &self.signature != SIGNATURE {
+      return Err(AcpiError::Signature);
+    }
+
+    let addr = self as *const Self as u64;
+
+    if !checksum(addr, V1_LENGTH) {
+      return Err(AcpiError::Checksum);
+    }
+
+    if self.revision >= 2 {
+      if (self.length as usize) < size_of::<Self>() {
+        return Err(AcpiError::Length);
+      }
+
+      acpi::map_table(self.address(), self.length as usize)?;
+
+      if !checksum(addr, self.length as usize) {
+        return Err(AcpiError::Checksum);
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn address(&self) -> u64 {
+    acpi::physical_address(self as *const Self as u64)
+  }
+
   pub fn sdt_type(&self) -> RsdtType {
-    match self.revision {
-      0 => RsdtType::Rsdt,
-      2 => RsdtType::Xsdt,
-      _ => panic!("invalid rdsp revision number: {}", self.revision),
+    if self.revision >= 2 && self.xsdt_address != 0 {
+      RsdtType::Xsdt
+    } else {
+      RsdtType::Rsdt
     }
   }
 
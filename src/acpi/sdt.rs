@@ -1,7 +1,6 @@
-use crate::memory::identity_map_pages;
+use super::acpi::{self, checksum, AcpiError};
 
-use core::mem::size_of;
-use x86_64::structures::paging::PageTableFlags;
+use core::{mem::size_of, str};
 
 #[repr(C, packed)]
 pub struct SdtHeader {
@@ -17,20 +16,42 @@ pub struct SdtHeader {
 }
 
 impl SdtHeader {
-  pub fn from_addr(addr: u64) -> &'static SdtHeader {
-    if let Err(_) = identity_map_pages(addr, addr + 1, PageTableFlags::PRESENT, true) {
-      log::trace!("page with the sdt at {:#x} was already identity mapped", addr);
+  // Only the header is mapped until its length has been checked, tables may span several pages.
+  pub fn from_addr(addr: u64) -> Result<&'static SdtHeader, AcpiError> {
+    let sdt = unsafe { &*(acpi::map_table(addr, size_of::<Self>())? as *const Self) };
+
+    if (sdt.length as usize) < size_of::<Self>() {
+      return Err(AcpiError::Length);
+    }
+
+    acpi::map_table(addr, sdt.length as usize)?;
+
+    sdt.validate()?;
+
+    Ok(sdt)
+  }
+
+  // Signatures are four upper case letters or digits, anything else means there is no table at this address.
+  fn validate(&self) -> Result<(), AcpiError> {
+    let signature = self.signature;
+
+    if !signature.iter().all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit()) {
+      return Err(AcpiError::Signature);
+    }
+
+    if !checksum(self as *const Self as u64, self.length as usize) {
+      return Err(AcpiError::Checksum);
     }
 
-    unsafe { &*(addr as *const Self) }
+    Ok(())
   }
 
   pub fn address(&self) -> u64 {
-    self as *const Self as u64
+    acpi::physical_address(self as *const Self as u64)
   }
 
   pub fn data_address(&self) -> u64 {
-    self.address() + size_of::<Self>() as u64
+    self as *const Self as u64 + size_of::<Self>() as u64
   }
 
   pub fn data_length(&self) -> usize {
@@ -38,7 +59,6 @@ impl SdtHeader {
   }
 
   pub fn signature(&self) -> &str {
-    // .expect("invalid utf-8 sequence inside sdt signature")
-    unsafe { core::str::from_utf8_unchecked(&self.signature) }
+    str::from_utf8(&self.signature).expect("invalid utf-8 sequence inside sdt signature")
   }
 }
@@ -0,0 +1,45 @@
+use crate::memory::stack::{KernelStack, DEFAULT_STACK_SIZE};
+
+use alloc::boxed::Box;
+use x86_64::{
+  instructions::{segmentation, tables},
+  structures::{
+    gdt::{Descriptor, GlobalDescriptorTable},
+    tss::TaskStateSegment,
+  },
+};
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+const IST_STACK_SIZE: u64 = 16 * 1024;
+
+pub fn init_cpu() {
+  let mut tss = TaskStateSegment::new();
+
+  tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = KernelStack::new(IST_STACK_SIZE)
+    .expect("failed to allocate the double fault stack")
+    .leak();
+
+  tss.privilege_stack_table[0] = KernelStack::new(DEFAULT_STACK_SIZE)
+    .expect("failed to allocate the privilege level 0 stack")
+    .leak();
+
+  let tss = Box::leak(Box::new(tss));
+
+  let mut gdt = GlobalDescriptorTable::new();
+  let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+  let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+  let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+  Box::leak(Box::new(gdt)).load();
+
+  // FS and GS are left alone, reloading them would clear the per-cpu base.
+  unsafe {
+    segmentation::set_cs(code_selector);
+    segmentation::load_ss(data_selector);
+    segmentation::load_ds(data_selector);
+    segmentation::load_es(data_selector);
+
+    tables::load_tss(tss_selector);
+  }
+}
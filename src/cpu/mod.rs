@@ -0,0 +1,41 @@
+pub mod gdt;
+pub mod percpu;
+
+use spin::Once;
+
+pub fn init() {
+  let cpu = percpu::init_cpu();
+
+  gdt::init_cpu();
+
+  log::info!("initialized per-cpu area for cpu {} (apic id {})", cpu.index, cpu.apic_id);
+}
+
+pub fn apic_id() -> u32 {
+  let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+
+  cpuid.ebx >> 24
+}
+
+// Cached, the physical memory mapping asks for every page it maps.
+pub fn has_1gib_pages() -> bool {
+  static HAS_1GIB_PAGES: Once<bool> = Once::new();
+
+  *HAS_1GIB_PAGES.call_once(|| {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) };
+
+    cpuid.edx & (1 << 26) != 0
+  })
+}
+
+pub fn has_pcid() -> bool {
+  let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+
+  cpuid.ecx & (1 << 17) != 0
+}
+
+pub fn has_invpcid() -> bool {
+  let cpuid = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+
+  cpuid.ebx & (1 << 10) != 0
+}
@@ -0,0 +1,124 @@
+use crate::memory::{address_space::AddressSpace, stack::KernelStack};
+
+use alloc::boxed::Box;
+use core::{
+  ptr,
+  sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
+
+pub const MAX_CPUS: usize = 64;
+pub const SCRATCH_STACKS: usize = 2;
+pub const SCRATCH_STACK_SIZE: u64 = 4096 * 4;
+
+const NO_CPU: AtomicPtr<PerCpu> = AtomicPtr::new(ptr::null_mut());
+
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+// Indices are handed out before a cpu is set up, it only counts as online once its slot in `CPUS` is published.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+#[macro_export]
+macro_rules! percpu {
+  () => {
+    $crate::cpu::percpu::current()
+  };
+  ($field:ident) => {
+    &$crate::cpu::percpu::current().$field
+  };
+}
+
+#[derive(Default)]
+pub struct CpuStats {
+  pub interrupts: AtomicU64,
+  pub exceptions: AtomicU64,
+  pub ipis: AtomicU64,
+}
+
+// The first field must stay `self_ptr`, `current` loads it through `gs:[0]`.
+#[repr(C)]
+pub struct PerCpu {
+  self_ptr: *const PerCpu,
+  pub index: usize,
+  pub apic_id: u32,
+  pub current_task: AtomicUsize,
+  // Holds a reference from `Arc::into_raw`, null while on the kernel's own page tables.
+  pub address_space: AtomicPtr<AddressSpace>,
+  pub scratch_stacks: [VirtAddr; SCRATCH_STACKS],
+  pub tlb_shootdown_pending: AtomicBool,
+  pub stats: CpuStats,
+}
+
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+// The bootloader leaves the gs base undefined, `try_current` needs it zero until `init_cpu` sets it.
+pub fn clear() {
+  GsBase::write(VirtAddr::zero());
+}
+
+pub fn init_cpu() -> &'static PerCpu {
+  let index = NEXT_INDEX.fetch_add(1, Ordering::AcqRel);
+
+  assert!(index < MAX_CPUS, "cannot bring up more than {} cpus", MAX_CPUS);
+
+  let mut scratch_stacks = [VirtAddr::zero(); SCRATCH_STACKS];
+
+  for top in scratch_stacks.iter_mut() {
+    *top = KernelStack::new(SCRATCH_STACK_SIZE)
+      .expect("failed to allocate a scratch stack")
+      .leak();
+  }
+
+  let cpu = Box::leak(Box::new(PerCpu {
+    self_ptr: ptr::null(),
+    index,
+    apic_id: super::apic_id(),
+    current_task: AtomicUsize::new(0),
+    address_space: AtomicPtr::new(ptr::null_mut()),
+    scratch_stacks,
+    tlb_shootdown_pending: AtomicBool::new(false),
+    stats: CpuStats::default(),
+  }));
+
+  cpu.self_ptr = cpu as *const PerCpu;
+
+  GsBase::write(VirtAddr::from_ptr(cpu.self_ptr));
+
+  CPUS[index].store(cpu, Ordering::Release);
+  ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+
+  cpu
+}
+
+pub fn current() -> &'static PerCpu {
+  let cpu: *const PerCpu;
+
+  unsafe {
+    asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, preserves_flags, readonly));
+
+    &*cpu
+  }
+}
+
+pub fn try_current() -> Option<&'static PerCpu> {
+  if GsBase::read().is_null() {
+    None
+  } else {
+    Some(current())
+  }
+}
+
+pub fn online_count() -> usize {
+  ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+pub fn cpu(index: usize) -> Option<&'static PerCpu> {
+  let cpu = CPUS.get(index)?.load(Ordering::Acquire);
+
+  unsafe { cpu.as_ref() }
+}
+
+pub fn online_cpus() -> impl Iterator<Item = &'static PerCpu> {
+  (0..NEXT_INDEX.load(Ordering::Acquire).min(MAX_CPUS)).filter_map(cpu)
+}
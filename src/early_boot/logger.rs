@@ -1,10 +1,18 @@
-use crate::utils::locked::Locked;
+use crate::{cpu::percpu, utils::locked::IrqLocked};
 
 use bootloader::boot_info::{FrameBuffer, FrameBufferInfo, PixelFormat};
-use core::fmt::{Result as FmtResult, Write};
+use core::{
+  fmt::{Result as FmtResult, Write},
+  sync::atomic::{AtomicUsize, Ordering},
+};
 use font8x8::{UnicodeFonts, BASIC_FONTS, BLOCK_UNICODE};
 use log::Log;
 
+const NO_OWNER: usize = usize::MAX;
+
+// The cpu currently writing a record, so that a panic knows whether the lock is held by itself.
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
 #[derive(Clone, Copy, Debug)]
 pub struct Color {
   pub r: u8,
@@ -113,7 +121,15 @@ impl Logger {
   }
 }
 
-impl Log for Locked<Logger> {
+fn current_cpu() -> usize {
+  percpu::try_current().map_or(0, |cpu| cpu.index)
+}
+
+pub fn held_by_current_cpu() -> bool {
+  OWNER.load(Ordering::Acquire) == current_cpu()
+}
+
+impl Log for IrqLocked<Logger> {
   fn enabled(&self, _: &log::Metadata) -> bool {
     true
   }
@@ -121,6 +137,8 @@ impl Log for Locked<Logger> {
   fn log(&self, record: &log::Record) {
     let mut logger = self.lock();
 
+    OWNER.store(current_cpu(), Ordering::Release);
+
     logger.set_color(match record.level() {
       log::Level::Trace => Color::from(0x76_26_71),
       log::Level::Debug => Color::from(0x39_b5_4a),
@@ -134,6 +152,8 @@ impl Log for Locked<Logger> {
     logger.set_color(Color::from(0xff_ff_ff));
 
     write!(logger, " - {}\n", record.args()).unwrap();
+
+    OWNER.store(NO_OWNER, Ordering::Release);
   }
 
   fn flush(&self) {}
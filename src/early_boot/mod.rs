@@ -1,18 +1,27 @@
 pub mod logger;
 
-use crate::utils::locked::Locked;
+use crate::utils::locked::IrqLocked;
 
 use bootloader::boot_info::FrameBuffer;
 use log::LevelFilter;
 use logger::Logger;
 use spin::Once;
 
-static LOGGER: Once<Locked<Logger>> = Once::new();
+static LOGGER: Once<IrqLocked<Logger>> = Once::new();
 
 // TODO: Make this a buffered logger + implement line scrolling
 pub fn init_logger(fb: &'static mut FrameBuffer) {
-  let logger = LOGGER.call_once(move || Locked::new(Logger::new(fb)));
+  let logger = LOGGER.call_once(move || IrqLocked::new(Logger::new(fb)));
 
   log::set_logger(logger).expect("logger has already been initialized");
   log::set_max_level(LevelFilter::Debug);
 }
+
+// Only frees the logger if this cpu holds it, another cpu releases it on its own.
+pub unsafe fn force_unlock_logger() {
+  if let Some(logger) = LOGGER.get() {
+    if logger::held_by_current_cpu() {
+      logger.force_unlock();
+    }
+  }
+}
@@ -0,0 +1,129 @@
+use crate::memory::mmio::{ioremap, CacheMode, MmioRegion};
+
+use spin::Once;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_SIZE: usize = 0x1000;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+static LAPIC: Once<MmioRegion> = Once::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiKind {
+  Fixed(u8),
+  Nmi,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiDestination {
+  Apic(u32),
+  Current,
+  All,
+  AllExcludingCurrent,
+}
+
+impl IpiKind {
+  fn icr_bits(self) -> u32 {
+    match self {
+      IpiKind::Fixed(vector) => vector as u32,
+      IpiKind::Nmi => 0b100 << 8,
+    }
+  }
+}
+
+impl IpiDestination {
+  fn shorthand(self) -> u32 {
+    let shorthand = match self {
+      IpiDestination::Apic(_) => 0b00,
+      IpiDestination::Current => 0b01,
+      IpiDestination::All => 0b10,
+      IpiDestination::AllExcludingCurrent => 0b11,
+    };
+
+    shorthand << 18
+  }
+}
+
+fn lapic() -> &'static MmioRegion {
+  LAPIC.get().expect("local apic has not been initialized")
+}
+
+fn read(reg: usize) -> u32 {
+  lapic().read(reg)
+}
+
+fn write(reg: usize, value: u32) {
+  lapic().write(reg, value)
+}
+
+pub fn init(spurious_vector: u8) {
+  let mut apic_base_msr = Msr::new(IA32_APIC_BASE);
+  let apic_base = unsafe { apic_base_msr.read() };
+  let base = apic_base & 0xf_ffff_f000;
+
+  LAPIC.call_once(|| ioremap(PhysAddr::new(base), LAPIC_SIZE, CacheMode::Uncached).expect("failed to map the local apic"));
+
+  unsafe {
+    apic_base_msr.write(apic_base | APIC_BASE_ENABLE);
+  }
+
+  write(REG_SPURIOUS, SPURIOUS_ENABLE | spurious_vector as u32);
+
+  log::info!("enabled local apic {} at {:#x}", id(), base);
+}
+
+pub fn is_initialized() -> bool {
+  LAPIC.is_completed()
+}
+
+pub fn id() -> u32 {
+  read(REG_ID) >> 24
+}
+
+pub fn end_of_interrupt() {
+  write(REG_EOI, 0);
+}
+
+pub fn send_ipi(destination: IpiDestination, kind: IpiKind) {
+  let apic_id = match destination {
+    IpiDestination::Apic(apic_id) => apic_id,
+    _ => 0,
+  };
+
+  while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+    core::hint::spin_loop();
+  }
+
+  x86_64::instructions::interrupts::without_interrupts(|| {
+    write(REG_ICR_HIGH, apic_id << 24);
+    write(REG_ICR_LOW, ICR_LEVEL_ASSERT | destination.shorthand() | kind.icr_bits());
+  });
+
+  while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+    core::hint::spin_loop();
+  }
+}
+
+pub fn send_fixed(apic_id: u32, vector: u8) {
+  send_ipi(IpiDestination::Apic(apic_id), IpiKind::Fixed(vector));
+}
+
+pub fn send_nmi(apic_id: u32) {
+  send_ipi(IpiDestination::Apic(apic_id), IpiKind::Nmi);
+}
+
+pub fn broadcast(vector: u8) {
+  send_ipi(IpiDestination::AllExcludingCurrent, IpiKind::Fixed(vector));
+}
@@ -1,13 +1,30 @@
+use super::apic;
+use crate::{
+  cpu::percpu,
+  memory::{tlb, vma},
+};
+
+use core::sync::atomic::Ordering;
 use x86_64::{
-  registers::control::Cr2,
+  registers::{control::Cr2, rflags::RFlags},
   structures::idt::{InterruptStackFrame, PageFaultErrorCode},
 };
 
+fn record_exception() {
+  if let Some(cpu) = percpu::try_current() {
+    cpu.stats.exceptions.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
 pub extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _: u64) -> ! {
+  record_exception();
+
   panic!("double fault exception, stack frame: {:?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+  record_exception();
+
   panic!(
     "general protection fault exception, error code: {:#x}, stack frame: {:?}",
     error_code, stack_frame
@@ -15,10 +32,31 @@ pub extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: Inte
 }
 
 pub extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-  panic!(
-    "page fault exception, accessed address: {:#x}, error code: {:?}, stack frame: {:?}",
-    Cr2::read(),
-    error_code,
-    stack_frame
-  );
+  record_exception();
+
+  let addr = Cr2::read();
+  let interruptible = RFlags::from_bits_truncate(stack_frame.cpu_flags).contains(RFlags::INTERRUPT_FLAG);
+
+  if let Err(err) = vma::handle_page_fault(addr, error_code, interruptible) {
+    panic!(
+      "page fault exception ({:?}), accessed address: {:#x}, error code: {:?}, stack frame: {:?}",
+      err,
+      addr,
+      error_code,
+      stack_frame
+    );
+  }
+}
+
+pub extern "x86-interrupt" fn tlb_shootdown_handler(_: InterruptStackFrame) {
+  let stats = crate::percpu!(stats);
+
+  stats.interrupts.fetch_add(1, Ordering::Relaxed);
+  stats.ipis.fetch_add(1, Ordering::Relaxed);
+
+  tlb::service_pending();
+
+  apic::end_of_interrupt();
 }
+
+pub extern "x86-interrupt" fn spurious_interrupt_handler(_: InterruptStackFrame) {}
@@ -1,18 +1,34 @@
+pub mod apic;
 mod handlers;
 
+use crate::cpu::gdt;
+
 use spin::Once;
 use x86_64::structures::idt::InterruptDescriptorTable;
 
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xf0;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
 static IDT: Once<InterruptDescriptorTable> = Once::new();
 
 pub fn init() {
   let mut idt = InterruptDescriptorTable::new();
 
-  idt.double_fault.set_handler_fn(handlers::double_fault_handler);
+  unsafe {
+    idt
+      .double_fault
+      .set_handler_fn(handlers::double_fault_handler)
+      .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+  }
+
   idt
     .general_protection_fault
     .set_handler_fn(handlers::general_protection_fault_handler);
   idt.page_fault.set_handler_fn(handlers::page_fault_handler);
+  idt[TLB_SHOOTDOWN_VECTOR as usize].set_handler_fn(handlers::tlb_shootdown_handler);
+  idt[SPURIOUS_VECTOR as usize].set_handler_fn(handlers::spurious_interrupt_handler);
 
   IDT.call_once(|| idt).load();
+
+  apic::init(SPURIOUS_VECTOR);
 }
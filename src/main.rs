@@ -2,13 +2,18 @@
 #![no_main]
 #![feature(abi_x86_interrupt, alloc_error_handler, asm, lang_items, panic_info_message)]
 
+extern crate alloc;
+
 mod acpi;
+mod cpu;
 mod early_boot;
 mod interrupts;
 mod memory;
 mod panic_handler;
 mod utils;
 
+use crate::memory::stack::KernelStack;
+
 use bootloader::{boot_info::KernelInfo, entry_point, BootInfo};
 use spin::Once;
 use x86_64::VirtAddr;
@@ -16,9 +21,13 @@ use x86_64::VirtAddr;
 pub static KERNEL_INFO: Once<KernelInfo> = Once::new();
 pub static PHYS_MEM_OFFSET: Once<VirtAddr> = Once::new();
 
+const BOOT_STACK_SIZE: u64 = 128 * 1024;
+
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+  cpu::percpu::clear();
+
   let mem_regions = &boot_info.memory_regions;
   let phys_mem_offset = boot_info
     .physical_memory_offset
@@ -41,18 +50,40 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
   memory::init(phys_mem_offset, mem_regions);
 
-  interrupts::init();
-
-  log::info!("loaded the interrupt descriptor table");
-
   let rsdp_addr = boot_info
     .rsdp_addr
     .into_option()
     .expect("cannot proceed without the rsdp structure");
 
+  // The bootloader's stack has no guard page and no known bounds, everything from here on runs on one of ours.
+  let boot_stack = KernelStack::new(BOOT_STACK_SIZE).expect("failed to allocate the boot stack");
+
+  log::info!("switching to the boot stack at {:#x}", boot_stack.top().as_u64());
+
+  unsafe { boot_stack.switch_to(kernel_init, rsdp_addr as usize) }
+}
+
+extern "C" fn kernel_init(rsdp_addr: usize) -> ! {
+  cpu::init();
+
+  interrupts::init();
+
+  log::info!("loaded the interrupt descriptor table");
+
+  // Everything the checks allocate is freed again, whatever is left over leaked.
+  #[cfg(feature = "alloc-track")]
+  memory::heap::tracker::checkpoint();
+
+  memory::dma::check();
+  memory::vmm::check();
+  memory::address_space::check();
+
+  #[cfg(feature = "alloc-track")]
+  memory::heap::tracker::dump_leaks(10);
+
   log::info!("found rsdp structure at {:#x}", rsdp_addr);
 
-  acpi::init(rsdp_addr);
+  acpi::init(rsdp_addr as u64);
 
   loop {
     unsafe { asm!("hlt") }
@@ -0,0 +1,574 @@
+use super::{
+  frame_allocator::{DeferredFrames, DEFERRED_RUNS},
+  page_table::{self, TABLE_FLAGS},
+  tlb, unmap_page,
+  vma::{self, Backing, FaultError, Vma, VmaError, VmaList, COPY_ON_WRITE},
+  FRAME_ALLOC, MAPPER,
+};
+use crate::{
+  cpu::{self, percpu},
+  utils::locked::IrqLocked,
+};
+
+use alloc::sync::Arc;
+use core::{
+  ptr,
+  sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use spin::Once;
+use x86_64::{
+  align_down, align_up,
+  instructions::interrupts,
+  registers::control::{Cr3, Cr4, Cr4Flags},
+  structures::{
+    idt::PageFaultErrorCode,
+    paging::{
+      mapper::{MapToError, TranslateResult, UnmapError},
+      FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+  },
+  PhysAddr, VirtAddr,
+};
+
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+const CR3_NO_FLUSH: u64 = 1 << 63;
+const PCID_COUNT: usize = 4096;
+
+static KERNEL_L4: Once<PhysFrame> = Once::new();
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Pcid 0 is never handed out, it stays with the kernel's own page tables.
+static PCIDS: IrqLocked<[u64; PCID_COUNT / 64]> = IrqLocked::new([0; PCID_COUNT / 64]);
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+  NotUserRange,
+  // Huge user pages cannot be shared copy-on-write.
+  HugePage(VirtAddr),
+  Map(MapToError<Size4KiB>),
+  Unmap(UnmapError),
+  Vma(VmaError),
+}
+
+fn allocate_pcid() -> Option<u16> {
+  if !pcid_enabled() {
+    return None;
+  }
+
+  let mut pcids = PCIDS.lock();
+  let pcid = (1..PCID_COUNT).find(|&pcid| pcids[pcid / 64] & (1 << (pcid % 64)) == 0)?;
+
+  pcids[pcid / 64] |= 1 << (pcid % 64);
+
+  Some(pcid as u16)
+}
+
+fn free_pcid(pcid: u16) {
+  let pcid = pcid as usize;
+
+  PCIDS.lock()[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+unsafe fn write_cr3(value: u64) {
+  asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+}
+
+fn check_user_range(start: u64, end: u64) -> Result<(), AddressSpaceError> {
+  if start >= end || end > USER_END {
+    return Err(AddressSpaceError::NotUserRange);
+  }
+
+  let mut kernel_mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+  let kernel_l4 = kernel_mapper.level_4_table();
+
+  let first = u16::from(VirtAddr::new(start).p4_index());
+  let last = u16::from(VirtAddr::new(end - 1).p4_index());
+
+  if (first..=last).any(|index| !kernel_l4[index as usize].is_unused()) {
+    return Err(AddressSpaceError::NotUserRange);
+  }
+
+  Ok(())
+}
+
+pub struct AddressSpace {
+  l4_frame: PhysFrame,
+  pcid: Option<u16>,
+  mapper: IrqLocked<OffsetPageTable<'static>>,
+  vmas: IrqLocked<VmaList>,
+  active_cpus: AtomicU64,
+  // Cpus that may hold valid translations under this pcid, every other cpu flushes it when switching here.
+  loaded_cpus: AtomicU64,
+}
+
+impl AddressSpace {
+  pub fn new() -> Result<Arc<Self>, AddressSpaceError> {
+    let mut kernel_mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+    let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+    let l4_frame = frame_alloc
+      .allocate_frame()
+      .ok_or(AddressSpaceError::Map(MapToError::FrameAllocationFailed))?;
+    let phys_mem_offset = kernel_mapper.phys_offset();
+    let l4 = page_table::table(phys_mem_offset, l4_frame);
+
+    for (entry, kernel_entry) in l4.iter_mut().zip(kernel_mapper.level_4_table().iter()) {
+      *entry = kernel_entry.clone();
+    }
+
+    drop(frame_alloc);
+    drop(kernel_mapper);
+
+    let mapper = unsafe { OffsetPageTable::new(page_table::table(phys_mem_offset, l4_frame), phys_mem_offset) };
+
+    Ok(Arc::new(Self {
+      l4_frame,
+      pcid: allocate_pcid(),
+      mapper: IrqLocked::new(mapper),
+      vmas: IrqLocked::new(VmaList::new()),
+      active_cpus: AtomicU64::new(0),
+      loaded_cpus: AtomicU64::new(0),
+    }))
+  }
+
+  pub fn l4_frame(&self) -> PhysFrame {
+    self.l4_frame
+  }
+
+  pub fn pcid(&self) -> Option<u16> {
+    self.pcid
+  }
+
+  pub fn map_user(&self, start: VirtAddr, end: VirtAddr, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+    let (start, end) = (align_down(start.as_u64(), 4096), align_up(end.as_u64(), 4096));
+
+    check_user_range(start, end)?;
+
+    let mut mapper = self.mapper.lock();
+    let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+    let phys_mem_offset = mapper.phys_offset();
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    for addr in (start..end).step_by(4096) {
+      let frame = frame_alloc
+        .allocate_frame()
+        .ok_or(AddressSpaceError::Map(MapToError::FrameAllocationFailed))?;
+
+      unsafe { ptr::write_bytes((phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr::<u8>(), 0, 4096) }
+
+      let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+
+      match unsafe { mapper.map_to(page, frame, flags, &mut *frame_alloc) } {
+        Ok(flush) => flush.ignore(),
+        Err(err) => {
+          unsafe { frame_alloc.deallocate_frame(frame) }
+
+          return Err(AddressSpaceError::Map(err));
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn unmap_user(&self, start: VirtAddr, end: VirtAddr) -> Result<(), AddressSpaceError> {
+    let (start, end) = (align_down(start.as_u64(), 4096), align_up(end.as_u64(), 4096));
+
+    check_user_range(start, end)?;
+
+    let mut deferred = DeferredFrames::new();
+    let mut result = Ok(());
+    let mut addr = start;
+
+    while result.is_ok() && addr < end {
+      let batch_start = addr;
+
+      let mut mapper = self.mapper.lock();
+
+      while addr < end && deferred.len() < DEFERRED_RUNS / 2 {
+        match unmap_page::<Size4KiB>(&mut mapper, addr, &mut deferred, true) {
+          Ok(()) | Err(UnmapError::PageNotMapped) => addr += 4096,
+          Err(err) => {
+            result = Err(AddressSpaceError::Unmap(err));
+            break;
+          }
+        }
+      }
+
+      let batch_end = addr.max(batch_start + 4096);
+      let phys_mem_offset = mapper.phys_offset();
+      let frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+      page_table::free_empty_tables(
+        mapper.level_4_table(),
+        phys_mem_offset,
+        VirtAddr::new(batch_start),
+        VirtAddr::new(batch_end),
+        &frame_alloc,
+        &mut deferred,
+      );
+
+      drop(frame_alloc);
+      drop(mapper);
+
+      self.invalidate(VirtAddr::new(batch_start), VirtAddr::new(batch_end));
+
+      unsafe { deferred.release(&mut FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock()) }
+    }
+
+    result
+  }
+
+  pub fn reserve(&self, vma: Vma) -> Result<(), AddressSpaceError> {
+    check_user_range(vma.start.as_u64(), vma.end.as_u64())?;
+
+    let vma = Vma {
+      flags: vma.flags | PageTableFlags::USER_ACCESSIBLE,
+      ..vma
+    };
+
+    self.vmas.lock().insert(vma).map_err(AddressSpaceError::Vma)
+  }
+
+  pub fn unreserve(&self, start: VirtAddr) -> Result<(), AddressSpaceError> {
+    let vma = self.vmas.lock().remove(start).ok_or(AddressSpaceError::NotUserRange)?;
+
+    self.unmap_user(vma.start, vma.end)
+  }
+
+  pub fn fork(&self) -> Result<Arc<Self>, AddressSpaceError> {
+    let child = AddressSpace::new()?;
+
+    *child.vmas.lock() = self.vmas.lock().clone();
+
+    let mut mapper = self.mapper.lock();
+    let mut child_mapper = child.mapper.lock();
+    let mut kernel_mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+    let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+    let phys_mem_offset = mapper.phys_offset();
+    let mut result = Ok(());
+
+    let walked = page_table::for_each_user_page(
+      mapper.level_4_table(),
+      kernel_mapper.level_4_table(),
+      phys_mem_offset,
+      |page, entry| {
+        if result.is_err() {
+          return;
+        }
+
+        let frame = match entry.frame() {
+          Ok(frame) => frame,
+          Err(_) => return,
+        };
+
+        let mut flags = entry.flags();
+
+        if flags.contains(PageTableFlags::WRITABLE) {
+          flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+          entry.set_flags(flags);
+        }
+
+        if frame_alloc.is_allocated(frame) {
+          frame_alloc.share(frame);
+        }
+
+        match unsafe { child_mapper.map_to_with_table_flags(page, frame, flags, TABLE_FLAGS, &mut *frame_alloc) } {
+          Ok(flush) => flush.ignore(),
+          Err(err) => {
+            if frame_alloc.is_allocated(frame) {
+              unsafe { frame_alloc.deallocate_frame(frame) }
+            }
+
+            result = Err(AddressSpaceError::Map(err));
+          }
+        }
+      },
+    );
+
+    let result = result.and(walked.map_err(AddressSpaceError::HugePage));
+
+    drop(frame_alloc);
+    drop(kernel_mapper);
+    drop(child_mapper);
+    drop(mapper);
+
+    self.invalidate(VirtAddr::zero(), VirtAddr::new(USER_END));
+
+    result.map(|()| child)
+  }
+
+  // Gives the faulting page a frame of its own, or just its write permission back if no one else shares the frame.
+  fn copy_on_write(&self, addr: VirtAddr, interruptible: bool) -> Result<(), FaultError> {
+    let mut mapper = self.mapper.lock();
+    let page = Page::<Size4KiB>::containing_address(addr);
+
+    let (frame, flags) = match mapper.translate(addr) {
+      TranslateResult::Mapped { frame, flags, .. } => (PhysFrame::containing_address(frame.start_address()), flags),
+      _ => return Err(FaultError::AccessDenied),
+    };
+
+    // Another cpu resolved it first, this one only had a stale translation.
+    if flags.contains(PageTableFlags::WRITABLE) {
+      tlb::flush_user_local(page.start_address(), page.start_address() + 4096_u64);
+
+      return Ok(());
+    }
+
+    if !flags.contains(COPY_ON_WRITE) {
+      return Err(FaultError::AccessDenied);
+    }
+
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+    // Other cpus keep faulting on their read-only entry until they find it writable, so this needs no shootdown.
+    if frame_alloc.refcount(frame) <= 1 {
+      unsafe { mapper.update_flags(page, flags) }
+        .map_err(|_| FaultError::AccessDenied)?
+        .flush();
+
+      return Ok(());
+    }
+
+    let copy = frame_alloc.allocate_frame().ok_or(FaultError::OutOfMemory)?;
+    let phys_mem_offset = mapper.phys_offset();
+
+    unsafe {
+      ptr::copy_nonoverlapping(
+        (phys_mem_offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+        (phys_mem_offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+        4096,
+      );
+    }
+
+    mapper.unmap(page).map_err(|_| FaultError::AccessDenied)?.1.ignore();
+
+    match unsafe { mapper.map_to_with_table_flags(page, copy, flags, TABLE_FLAGS, &mut *frame_alloc) } {
+      Ok(flush) => flush.ignore(),
+      Err(_) => panic!("failed to remap copy-on-write page {:#x}", addr.as_u64()),
+    }
+
+    drop(frame_alloc);
+    drop(mapper);
+
+    self.invalidate_fault(page.start_address(), page.start_address() + 4096_u64, interruptible);
+
+    // Other cpus may have read the shared frame through their stale entry until now.
+    let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+    unsafe { frame_alloc.deallocate_frame(frame) }
+
+    Ok(())
+  }
+
+  // `interruptible` tells whether the faulting code ran with interrupts enabled, and so held no spinlock.
+  pub fn handle_fault(&self, addr: VirtAddr, error_code: PageFaultErrorCode, interruptible: bool) -> Result<(), FaultError> {
+    let write_to_present = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+
+    // Copy-on-write pages carry their permission in the entry, they may lie outside of any area.
+    if error_code.contains(write_to_present) {
+      return self.copy_on_write(addr, interruptible);
+    }
+
+    let flags = vma::resolve(self.vmas.lock().find(addr), error_code)?;
+    let mut mapper = self.mapper.lock();
+
+    vma::populate(&mut mapper, addr, flags)
+  }
+
+  pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+    self.mapper.lock().translate_addr(addr)
+  }
+
+  fn invalidate(&self, start: VirtAddr, end: VirtAddr) {
+    self.loaded_cpus.store(self.active_cpus.load(Ordering::Acquire), Ordering::Release);
+
+    tlb::flush_user_range(start, end);
+  }
+
+  // Cpus switching here later flush the pcid, it is no longer marked loaded for them.
+  fn invalidate_fault(&self, start: VirtAddr, end: VirtAddr, interruptible: bool) {
+    let bit = 1 << percpu::current().index;
+
+    self.loaded_cpus.store(bit, Ordering::SeqCst);
+
+    if self.active_cpus.load(Ordering::SeqCst) & !bit == 0 {
+      tlb::flush_user_local(start, end);
+    } else if interruptible {
+      self.invalidate(start, end);
+    } else {
+      panic!(
+        "copy-on-write fault at {:#x} with interrupts disabled while other cpus run on the address space",
+        start.as_u64()
+      );
+    }
+  }
+
+  pub fn activate(self: &Arc<Self>) {
+    interrupts::without_interrupts(|| {
+      let cpu = percpu::current();
+      let bit = 1 << cpu.index;
+
+      self.active_cpus.fetch_or(bit, Ordering::AcqRel);
+
+      let mut cr3 = self.l4_frame.start_address().as_u64();
+
+      if let Some(pcid) = self.pcid {
+        cr3 |= pcid as u64;
+
+        if self.loaded_cpus.fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+          cr3 |= CR3_NO_FLUSH;
+        }
+      }
+
+      unsafe { write_cr3(cr3) }
+
+      let current = Arc::into_raw(self.clone());
+      let previous = cpu.address_space.swap(current as *mut Self, Ordering::AcqRel);
+
+      release(previous, current, bit);
+    })
+  }
+}
+
+fn release(previous: *mut AddressSpace, current: *const AddressSpace, bit: u64) {
+  if previous.is_null() {
+    return;
+  }
+
+  let previous = unsafe { Arc::from_raw(previous) };
+
+  // Switching to the address space that was already active leaves it active.
+  if !ptr::eq(Arc::as_ptr(&previous), current) {
+    previous.active_cpus.fetch_and(!bit, Ordering::AcqRel);
+  }
+}
+
+impl Drop for AddressSpace {
+  fn drop(&mut self) {
+    let mut mapper = self.mapper.lock();
+    let mut kernel_mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+    let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+    let phys_mem_offset = mapper.phys_offset();
+    let kernel_l4: &PageTable = kernel_mapper.level_4_table();
+
+    for (entry, kernel_entry) in mapper.level_4_table().iter_mut().zip(kernel_l4.iter()) {
+      if !page_table::is_user_entry(entry, kernel_entry) {
+        continue;
+      }
+
+      if let Ok(frame) = entry.frame() {
+        page_table::free_tree(phys_mem_offset, frame, 3, &mut frame_alloc);
+      }
+
+      entry.set_unused();
+    }
+
+    unsafe { frame_alloc.deallocate_frame(self.l4_frame) }
+
+    drop(frame_alloc);
+    drop(kernel_mapper);
+    drop(mapper);
+
+    if let Some(pcid) = self.pcid {
+      free_pcid(pcid);
+    }
+  }
+}
+
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+pub fn current() -> Option<Arc<AddressSpace>> {
+  let address_space = percpu::try_current()?.address_space.load(Ordering::Acquire);
+
+  if address_space.is_null() {
+    return None;
+  }
+
+  unsafe {
+    Arc::increment_strong_count(address_space);
+
+    Some(Arc::from_raw(address_space))
+  }
+}
+
+pub fn activate_kernel() {
+  interrupts::without_interrupts(|| {
+    let cpu = percpu::current();
+    let l4_frame = *KERNEL_L4.get().expect("address spaces have not been initialized");
+
+    unsafe { write_cr3(l4_frame.start_address().as_u64()) }
+
+    release(cpu.address_space.swap(ptr::null_mut(), Ordering::AcqRel), ptr::null(), 1 << cpu.index);
+  })
+}
+
+pub fn check() {
+  let page = (1..256_u64)
+    .map(|index| VirtAddr::new(index << 39))
+    .find(|addr| check_user_range(addr.as_u64(), addr.as_u64() + 2 * 4096).is_ok())
+    .expect("no level 4 entry is free for user pages");
+  let lazy = page + 4096_u64;
+  let value = |addr: VirtAddr| unsafe { addr.as_ptr::<u64>().read_volatile() };
+
+  let parent = AddressSpace::new().expect("failed to create an address space");
+
+  parent
+    .map_user(page, lazy, PageTableFlags::WRITABLE)
+    .expect("failed to map a user page");
+  parent
+    .reserve(Vma::new(lazy, lazy + 4096_u64, PageTableFlags::WRITABLE, Backing::Anonymous))
+    .expect("failed to reserve a user area");
+  parent.activate();
+
+  unsafe {
+    page.as_mut_ptr::<u64>().write_volatile(1);
+    lazy.as_mut_ptr::<u64>().write_volatile(1);
+  }
+
+  let child = parent.fork().expect("failed to fork an address space");
+
+  child.activate();
+
+  unsafe { page.as_mut_ptr::<u64>().write_volatile(2) }
+
+  assert!(value(page) == 2 && value(lazy) == 1, "forked address space reads wrong values");
+  assert_ne!(parent.translate(page), child.translate(page), "copy-on-write page was not copied");
+
+  parent.activate();
+
+  assert!(value(page) == 1, "copy-on-write fault changed the parent's page");
+
+  parent.unreserve(lazy).expect("failed to unreserve a user area");
+  activate_kernel();
+
+  log::info!(
+    "forked address space at {:#x} with pcid {:?}",
+    child.l4_frame().start_address().as_u64(),
+    child.pcid()
+  );
+}
+
+pub fn pcid_enabled() -> bool {
+  PCID_ENABLED.load(Ordering::Acquire)
+}
+
+pub fn init() {
+  let (l4_frame, flags) = Cr3::read();
+
+  KERNEL_L4.call_once(|| l4_frame);
+
+  // Without invpcid kernel mappings cached under other pcids could not be invalidated.
+  if cpu::has_pcid() && cpu::has_invpcid() && flags.is_empty() {
+    unsafe { Cr4::update(|flags| *flags |= Cr4Flags::PCID) }
+
+    PCID_ENABLED.store(true, Ordering::Release);
+
+    log::info!("enabled process-context identifiers");
+  }
+}
@@ -0,0 +1,143 @@
+use super::{
+  frame_allocator::{Zone, MAX_ORDER},
+  FRAME_ALLOC,
+};
+use crate::PHYS_MEM_OFFSET;
+
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+// Buffers are single buddy blocks, none of them is aligned beyond the largest one.
+const MAX_ALIGNMENT: usize = 4096 << (MAX_ORDER - 1);
+const ISA_LIMIT: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaLimit {
+  Isa,
+  Bits32,
+  Bits64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DmaConstraints {
+  pub limit: DmaLimit,
+  pub alignment: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaError {
+  InvalidAlignment,
+  OutOfMemory,
+}
+
+pub struct DmaBuffer {
+  phys: PhysAddr,
+  virt: VirtAddr,
+  frames: usize,
+}
+
+impl Default for DmaConstraints {
+  fn default() -> Self {
+    Self {
+      limit: DmaLimit::Bits64,
+      alignment: 4096,
+    }
+  }
+}
+
+impl DmaLimit {
+  fn zone(self) -> Zone {
+    match self {
+      DmaLimit::Isa => Zone::Dma,
+      DmaLimit::Bits32 => Zone::Dma32,
+      DmaLimit::Bits64 => Zone::Normal,
+    }
+  }
+}
+
+impl DmaBuffer {
+  pub fn phys_addr(&self) -> PhysAddr {
+    self.phys
+  }
+
+  pub fn virt_addr(&self) -> VirtAddr {
+    self.virt
+  }
+
+  pub fn len(&self) -> usize {
+    self.frames * 4096
+  }
+
+  pub fn as_mut_ptr<T>(&self) -> *mut T {
+    self.virt.as_mut_ptr()
+  }
+}
+
+impl Drop for DmaBuffer {
+  fn drop(&mut self) {
+    let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+    unsafe {
+      frame_alloc.deallocate_contiguous(PhysFrame::containing_address(self.phys), self.frames);
+    }
+  }
+}
+
+pub fn alloc(size: usize, constraints: DmaConstraints) -> Result<DmaBuffer, DmaError> {
+  if !constraints.alignment.is_power_of_two() || constraints.alignment > MAX_ALIGNMENT {
+    return Err(DmaError::InvalidAlignment);
+  }
+
+  let frames = (size.max(1) + 4095) / 4096;
+  let align = (constraints.alignment / 4096).max(1);
+
+  let frame = FRAME_ALLOC
+    .get()
+    .expect("frame allocator has not been initialized")
+    .lock()
+    .allocate_contiguous(frames, align, constraints.limit.zone())
+    .ok_or(DmaError::OutOfMemory)?;
+
+  let phys = frame.start_address();
+  let virt = *PHYS_MEM_OFFSET.get().expect("physical memory offset is unknown") + phys.as_u64();
+
+  unsafe {
+    virt.as_mut_ptr::<u8>().write_bytes(0, frames * 4096);
+  }
+
+  log::trace!("allocated {} dma frames at {:#x}", frames, phys.as_u64());
+
+  Ok(DmaBuffer { phys, virt, frames })
+}
+
+// Allocates a buffer under the strictest limit, so that a broken zone setup shows up at boot rather than in a driver.
+pub fn check() {
+  let constraints = DmaConstraints {
+    limit: DmaLimit::Isa,
+    alignment: 64 * 1024,
+  };
+
+  let buffer = alloc(8192, constraints).expect("failed to allocate an isa dma buffer");
+  let phys = buffer.phys_addr().as_u64();
+  let zeroed = (0..buffer.len()).all(|offset| unsafe { *buffer.as_mut_ptr::<u8>().add(offset) } == 0);
+
+  assert!(
+    phys + buffer.len() as u64 <= ISA_LIMIT,
+    "isa dma buffer at {:#x} is out of reach",
+    phys
+  );
+  assert!(phys % constraints.alignment as u64 == 0, "dma buffer at {:#x} is misaligned", phys);
+  assert!(zeroed, "dma buffer at {:#x} was not zeroed", phys);
+
+  let oversized = DmaConstraints {
+    alignment: MAX_ALIGNMENT * 2,
+    ..constraints
+  };
+
+  assert_eq!(alloc(4096, oversized).err(), Some(DmaError::InvalidAlignment));
+
+  log::info!(
+    "allocated isa dma buffer at {:#x}, mapped at {:#x}",
+    phys,
+    buffer.virt_addr().as_u64()
+  );
+}
@@ -1,27 +1,402 @@
 use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
+use core::mem::size_of;
 use x86_64::{
-  structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
-  PhysAddr,
+  align_down, align_up,
+  structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+  PhysAddr, VirtAddr,
 };
 
+const FRAME_SIZE: u64 = 4096;
+
+// Blocks go up to 2^(MAX_ORDER - 1) frames, 4 MiB.
+pub const MAX_ORDER: usize = 11;
+
+const FRAME_FREE: u8 = 1 << 0;
+const FRAME_RESERVED: u8 = 1 << 1;
+
+// Physical frame number 0 is never handed out, which lets it double as the end of a free list.
+const NO_FRAME: u64 = 0;
+
+pub const DEFERRED_RUNS: usize = 256;
+
+// `refs` counts the mappings sharing an allocated frame, it is freed once the last one lets go of it.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FrameInfo {
+  flags: u8,
+  order: u8,
+  refs: u16,
+}
+
+// Free blocks are linked through their first frame, reached through the physical memory mapping.
+#[repr(C)]
+struct FreeNode {
+  prev: u64,
+  next: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+  Dma = 0,
+  Dma32 = 1,
+  Normal = 2,
+}
+
+// Zone limits are multiples of the largest block size, so a buddy block never spans two zones.
+impl Zone {
+  pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+  pub fn limit(self) -> u64 {
+    match self {
+      Zone::Dma => 16 * 1024 * 1024,
+      Zone::Dma32 => 4 * 1024 * 1024 * 1024,
+      Zone::Normal => u64::MAX,
+    }
+  }
+
+  fn of(pfn: u64) -> Zone {
+    let addr = pfn * FRAME_SIZE;
+
+    Zone::ALL
+      .iter()
+      .copied()
+      .find(|zone| addr < zone.limit())
+      .unwrap_or(Zone::Normal)
+  }
+}
+
+#[derive(Clone, Copy)]
+struct ZoneFreeArea {
+  free_lists: [u64; MAX_ORDER],
+  total_frames: usize,
+  free_frames: usize,
+}
+
 pub struct GlobalFrameAllocator {
-  mem_maps: &'static MemoryRegions,
-  next: usize,
+  phys_mem_offset: VirtAddr,
+  frames: &'static mut [FrameInfo],
+  zones: [ZoneFreeArea; 3],
+}
+
+fn order_for(count: usize) -> usize {
+  count.next_power_of_two().trailing_zeros() as usize
 }
 
 impl GlobalFrameAllocator {
-  pub fn new(mem_maps: &'static MemoryRegions) -> Self {
-    Self { mem_maps, next: 0 }
+  pub fn new(mem_regions: &'static MemoryRegions, phys_mem_offset: VirtAddr) -> Self {
+    let usable = || mem_regions.iter().filter(|region| region.kind == MemoryRegionKind::Usable);
+
+    let frame_count = (usable().map(|region| region.end).max().expect("no usable memory regions") / FRAME_SIZE) as usize;
+    let metadata_size = align_up((frame_count * size_of::<FrameInfo>()) as u64, FRAME_SIZE);
+
+    let metadata_start = usable()
+      .map(|region| (align_up(region.start, FRAME_SIZE), align_down(region.end, FRAME_SIZE)))
+      .find(|&(start, end)| start != 0 && end.saturating_sub(start) >= metadata_size)
+      .map(|(start, _)| start)
+      .expect("no usable memory region can hold the frame allocator metadata");
+    let metadata_end = metadata_start + metadata_size;
+
+    let frames = unsafe {
+      core::slice::from_raw_parts_mut((phys_mem_offset + metadata_start).as_mut_ptr::<FrameInfo>(), frame_count)
+    };
+
+    for frame in frames.iter_mut() {
+      *frame = FrameInfo {
+        flags: FRAME_RESERVED,
+        order: 0,
+        refs: 0,
+      };
+    }
+
+    let empty_zone = ZoneFreeArea {
+      free_lists: [NO_FRAME; MAX_ORDER],
+      total_frames: 0,
+      free_frames: 0,
+    };
+
+    let mut allocator = Self {
+      phys_mem_offset,
+      frames,
+      zones: [empty_zone; 3],
+    };
+
+    for region in usable() {
+      let start = align_up(region.start, FRAME_SIZE).max(FRAME_SIZE);
+      let end = align_down(region.end, FRAME_SIZE);
+
+      if start >= end {
+        continue;
+      }
+
+      if start < metadata_end && metadata_start < end {
+        allocator.add_range(start, metadata_start);
+        allocator.add_range(metadata_end, end);
+      } else {
+        allocator.add_range(start, end);
+      }
+    }
+
+    log::info!(
+      "frame allocator manages {} frames, frame metadata uses {:#x} bytes at {:#x}",
+      allocator.total_frames(),
+      metadata_size,
+      metadata_start
+    );
+
+    for &zone in Zone::ALL.iter() {
+      log::debug!("zone {:?} has {} frames", zone, allocator.zones[zone as usize].total_frames);
+    }
+
+    allocator
   }
 
-  fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-    self
-      .mem_maps
-      .iter()
-      .filter(|region| region.kind == MemoryRegionKind::Usable)
-      .map(|region| region.start..region.end)
-      .flat_map(|range| range.step_by(4096))
-      .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+  fn add_range(&mut self, start: u64, end: u64) {
+    let mut pfn = start / FRAME_SIZE;
+    let end_pfn = end / FRAME_SIZE;
+
+    while pfn < end_pfn {
+      let mut order = (pfn.trailing_zeros() as usize).min(MAX_ORDER - 1);
+
+      while pfn + (1 << order) > end_pfn {
+        order -= 1;
+      }
+
+      for frame in &mut self.frames[pfn as usize..(pfn + (1 << order)) as usize] {
+        frame.flags = 0;
+      }
+
+      self.zones[Zone::of(pfn) as usize].total_frames += 1 << order;
+      self.free_block(pfn, order);
+
+      pfn += 1 << order;
+    }
+  }
+
+  fn node(&self, pfn: u64) -> &'static mut FreeNode {
+    unsafe { &mut *(self.phys_mem_offset + pfn * FRAME_SIZE).as_mut_ptr() }
+  }
+
+  fn push_free(&mut self, pfn: u64, order: usize) {
+    let zone = Zone::of(pfn) as usize;
+    let head = self.zones[zone].free_lists[order];
+
+    *self.node(pfn) = FreeNode {
+      prev: NO_FRAME,
+      next: head,
+    };
+
+    if head != NO_FRAME {
+      self.node(head).prev = pfn;
+    }
+
+    self.zones[zone].free_lists[order] = pfn;
+    self.frames[pfn as usize] = FrameInfo {
+      flags: FRAME_FREE,
+      order: order as u8,
+      refs: 0,
+    };
+  }
+
+  fn remove_free(&mut self, pfn: u64, order: usize) {
+    let node = self.node(pfn);
+
+    if node.prev == NO_FRAME {
+      self.zones[Zone::of(pfn) as usize].free_lists[order] = node.next;
+    } else {
+      self.node(node.prev).next = node.next;
+    }
+
+    if node.next != NO_FRAME {
+      self.node(node.next).prev = node.prev;
+    }
+
+    self.frames[pfn as usize].flags &= !FRAME_FREE;
+  }
+
+  // Prefers the highest zone allowed, so that low memory stays available for devices that need it.
+  fn allocate_block(&mut self, order: usize, max_zone: Zone) -> Option<u64> {
+    let (zone, mut current) = Zone::ALL[..=max_zone as usize].iter().rev().find_map(|&zone| {
+      (order..MAX_ORDER)
+        .find(|&current| self.zones[zone as usize].free_lists[current] != NO_FRAME)
+        .map(|current| (zone, current))
+    })?;
+    let pfn = self.zones[zone as usize].free_lists[current];
+
+    self.remove_free(pfn, current);
+
+    while current > order {
+      current -= 1;
+      self.push_free(pfn + (1 << current), current);
+    }
+
+    self.frames[pfn as usize] = FrameInfo {
+      flags: 0,
+      order: 0,
+      refs: 1,
+    };
+    self.zones[zone as usize].free_frames -= 1 << order;
+
+    Some(pfn)
+  }
+
+  fn free_block(&mut self, mut pfn: u64, mut order: usize) {
+    self.zones[Zone::of(pfn) as usize].free_frames += 1 << order;
+
+    while order < MAX_ORDER - 1 {
+      let buddy = pfn ^ (1 << order);
+
+      match self.frames.get(buddy as usize) {
+        Some(info) if info.flags & FRAME_FREE != 0 && info.order as usize == order => {
+          self.remove_free(buddy, order);
+
+          pfn = pfn.min(buddy);
+          order += 1;
+        }
+        _ => break,
+      }
+    }
+
+    self.push_free(pfn, order);
+  }
+
+  fn is_free(&self, pfn: u64) -> bool {
+    (0..MAX_ORDER).any(|order| {
+      let info = self.frames[(pfn & !((1 << order) - 1)) as usize];
+
+      info.flags & FRAME_FREE != 0 && info.order as usize == order
+    })
+  }
+
+  fn is_in_use(&self, pfn: u64) -> bool {
+    match self.frames.get(pfn as usize) {
+      Some(info) => info.flags & FRAME_RESERVED == 0 && !self.is_free(pfn),
+      None => false,
+    }
+  }
+
+  pub fn allocate_contiguous(&mut self, count: usize, align: usize, max_zone: Zone) -> Option<PhysFrame> {
+    let order = order_for(count.max(align));
+
+    if count == 0 || order >= MAX_ORDER {
+      return None;
+    }
+
+    let pfn = self.allocate_block(order, max_zone)?;
+
+    for extra in count..(1 << order) {
+      self.free_block(pfn + extra as u64, 0);
+    }
+
+    for frame in &mut self.frames[pfn as usize..pfn as usize + count] {
+      frame.refs = 1;
+    }
+
+    Some(PhysFrame::containing_address(PhysAddr::new(pfn * FRAME_SIZE)))
+  }
+
+  pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+    for frame in PhysFrame::range(start, start + count as u64) {
+      self.deallocate_frame(frame);
+    }
+  }
+
+  pub fn is_allocated(&self, frame: PhysFrame) -> bool {
+    self.is_in_use(frame.start_address().as_u64() / FRAME_SIZE)
+  }
+
+  pub fn share(&mut self, frame: PhysFrame) {
+    let pfn = frame.start_address().as_u64() / FRAME_SIZE;
+
+    if !self.is_in_use(pfn) {
+      panic!("tried to share frame {:#x} which is not allocated", frame.start_address().as_u64());
+    }
+
+    let info = &mut self.frames[pfn as usize];
+
+    info.refs = info.refs.checked_add(1).expect("frame reference count overflowed");
+  }
+
+  pub fn refcount(&self, frame: PhysFrame) -> u16 {
+    let pfn = frame.start_address().as_u64() / FRAME_SIZE;
+
+    if self.is_in_use(pfn) {
+      self.frames[pfn as usize].refs
+    } else {
+      0
+    }
+  }
+
+  pub fn total_frames(&self) -> usize {
+    self.zones.iter().map(|zone| zone.total_frames).sum()
+  }
+
+  pub fn free_frames(&self) -> usize {
+    self.zones.iter().map(|zone| zone.free_frames).sum()
+  }
+
+  pub fn zone_frames(&self, zone: Zone) -> (usize, usize) {
+    let zone = &self.zones[zone as usize];
+
+    (zone.total_frames, zone.free_frames)
+  }
+}
+
+pub struct DeferredFrames {
+  runs: [(u64, u64); DEFERRED_RUNS],
+  len: usize,
+}
+
+impl DeferredFrames {
+  pub const fn new() -> Self {
+    Self {
+      runs: [(0, 0); DEFERRED_RUNS],
+      len: 0,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  // Returns `false` when there is no room left, the frames then have to stay where they are.
+  pub fn try_push(&mut self, start: PhysFrame, count: u64) -> bool {
+    let pfn = start.start_address().as_u64() / FRAME_SIZE;
+
+    if let Some(last) = self.runs[..self.len].last_mut() {
+      if last.0 + last.1 == pfn {
+        last.1 += count;
+
+        return true;
+      }
+    }
+
+    if self.len == DEFERRED_RUNS {
+      return false;
+    }
+
+    self.runs[self.len] = (pfn, count);
+    self.len += 1;
+
+    true
+  }
+
+  pub fn push(&mut self, start: PhysFrame, count: u64) {
+    assert!(self.try_push(start, count), "too many deferred frames");
+  }
+
+  // Only call once the translations have been flushed on every cpu.
+  pub unsafe fn release(&mut self, frame_alloc: &mut GlobalFrameAllocator) {
+    for &(start, count) in &self.runs[..self.len] {
+      for pfn in start..start + count {
+        let frame = PhysFrame::containing_address(PhysAddr::new(pfn * FRAME_SIZE));
+
+        if frame_alloc.is_allocated(frame) {
+          frame_alloc.deallocate_frame(frame);
+        }
+      }
+    }
+
+    self.len = 0;
   }
 }
 
@@ -30,7 +405,26 @@ unsafe impl Sync for GlobalFrameAllocator {}
 
 unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
   fn allocate_frame(&mut self) -> Option<PhysFrame> {
-    self.next += 1;
-    self.usable_frames().nth(self.next - 1)
+    self
+      .allocate_block(0, Zone::Normal)
+      .map(|pfn| PhysFrame::containing_address(PhysAddr::new(pfn * FRAME_SIZE)))
+  }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+  unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+    let pfn = frame.start_address().as_u64() / FRAME_SIZE;
+
+    if !self.is_in_use(pfn) {
+      panic!("tried to free frame {:#x} which is not allocated", frame.start_address().as_u64());
+    }
+
+    let info = &mut self.frames[pfn as usize];
+
+    info.refs = info.refs.saturating_sub(1);
+
+    if info.refs == 0 {
+      self.free_block(pfn, 0);
+    }
   }
 }
@@ -0,0 +1,296 @@
+use super::{checked_alloc, checked_dealloc, HEAP_MAX_SIZE};
+use crate::{
+  memory::{
+    map_new_pages,
+    vmm::{self, Region},
+  },
+  utils::locked::IrqLocked,
+};
+
+use core::{
+  alloc::Layout,
+  ptr,
+  sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{align_down, align_up, structures::paging::PageTableFlags};
+
+// A shadow byte is 0 if all 8 bytes are accessible, 1 to 7 for that many leading bytes.
+const GRANULE: u64 = 8;
+const SHADOW_SCALE: u64 = 3;
+
+const HEAP_REDZONE: u8 = 0xfa;
+const FREED: u8 = 0xfb;
+const UNALLOCATED: u8 = 0xfc;
+
+const REDZONE_SIZE: usize = 16;
+
+const QUARANTINE_ENTRIES: usize = 1024;
+const QUARANTINE_BYTES: usize = 4 * 1024 * 1024;
+
+static HEAP_START: AtomicU64 = AtomicU64::new(0);
+static HEAP_END: AtomicU64 = AtomicU64::new(0);
+static SHADOW_START: AtomicU64 = AtomicU64::new(0);
+static SHADOW_MAPPED_END: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+struct QuarantinedBlock {
+  raw: usize,
+  size: usize,
+  align: usize,
+}
+
+struct Quarantine {
+  blocks: [QuarantinedBlock; QUARANTINE_ENTRIES],
+  head: usize,
+  count: usize,
+  bytes: usize,
+}
+
+const NO_BLOCK: QuarantinedBlock = QuarantinedBlock { raw: 0, size: 0, align: 0 };
+
+static QUARANTINE: IrqLocked<Quarantine> = IrqLocked::new(Quarantine {
+  blocks: [NO_BLOCK; QUARANTINE_ENTRIES],
+  head: 0,
+  count: 0,
+  bytes: 0,
+});
+
+impl Quarantine {
+  fn push(&mut self, block: QuarantinedBlock) {
+    self.blocks[(self.head + self.count) % QUARANTINE_ENTRIES] = block;
+    self.count += 1;
+    self.bytes += block.size;
+  }
+
+  fn pop_over_limit(&mut self) -> Option<QuarantinedBlock> {
+    if self.count < QUARANTINE_ENTRIES && self.bytes <= QUARANTINE_BYTES {
+      return None;
+    }
+
+    let block = self.blocks[self.head];
+
+    self.head = (self.head + 1) % QUARANTINE_ENTRIES;
+    self.count -= 1;
+    self.bytes -= block.size;
+
+    Some(block)
+  }
+}
+
+fn shadow_for(addr: u64) -> *mut u8 {
+  let offset = (addr - HEAP_START.load(Ordering::Relaxed)) >> SHADOW_SCALE;
+
+  (SHADOW_START.load(Ordering::Relaxed) + offset) as *mut u8
+}
+
+fn in_heap(addr: u64) -> bool {
+  (HEAP_START.load(Ordering::Relaxed)..HEAP_END.load(Ordering::Acquire)).contains(&addr)
+}
+
+fn poison(addr: u64, size: u64, value: u8) {
+  unsafe { ptr::write_bytes(shadow_for(addr), value, (size >> SHADOW_SCALE) as usize) }
+}
+
+fn unpoison(addr: u64, size: u64) {
+  unsafe {
+    ptr::write_bytes(shadow_for(addr), 0, (size >> SHADOW_SCALE) as usize);
+
+    if size % GRANULE != 0 {
+      *shadow_for(addr + align_down(size, GRANULE)) = (size % GRANULE) as u8;
+    }
+  }
+}
+
+fn shadow_value(addr: u64) -> u8 {
+  unsafe { *shadow_for(addr) }
+}
+
+fn is_poisoned(addr: u64) -> bool {
+  let shadow = shadow_value(addr);
+
+  shadow != 0 && (shadow & 0x80 != 0 || (addr % GRANULE) as u8 >= shadow)
+}
+
+fn describe(shadow: u8) -> &'static str {
+  match shadow {
+    HEAP_REDZONE => "heap-buffer-overflow",
+    FREED => "use-after-free",
+    UNALLOCATED => "access to unallocated heap memory",
+    _ => "heap-buffer-overflow",
+  }
+}
+
+// The panic handler prints the backtrace of the offending access.
+fn report(addr: u64, size: usize, write: bool, bad_addr: u64) -> ! {
+  panic!(
+    "kasan: {} on {} of {} bytes at {:#x}, first bad byte at {:#x} (shadow {:#x})",
+    describe(shadow_value(bad_addr)),
+    if write { "write" } else { "read" },
+    size,
+    addr,
+    bad_addr,
+    shadow_value(bad_addr)
+  );
+}
+
+// Only the copies `realloc` makes are checked, nothing is built with compiler instrumentation.
+fn check(addr: u64, size: usize, write: bool) {
+  if size == 0 || !in_heap(addr) {
+    return;
+  }
+
+  let end = addr.saturating_add(size as u64).min(HEAP_END.load(Ordering::Acquire));
+  let mut current = addr;
+
+  while current < end {
+    if current % GRANULE == 0 && end - current >= GRANULE && shadow_value(current) == 0 {
+      current += GRANULE;
+      continue;
+    }
+
+    if is_poisoned(current) {
+      report(addr, size, write, current);
+    }
+
+    current += 1;
+  }
+}
+
+unsafe fn copy(src: *const u8, dst: *mut u8, count: usize) {
+  check(src as u64, count, false);
+  check(dst as u64, count, true);
+
+  ptr::copy_nonoverlapping(src, dst, count)
+}
+
+fn left_redzone(layout: Layout) -> usize {
+  REDZONE_SIZE.max(layout.align())
+}
+
+fn shadowed_layout(layout: Layout) -> Layout {
+  let size = left_redzone(layout) + align_up(layout.size() as u64, GRANULE) as usize + REDZONE_SIZE;
+
+  unsafe { Layout::from_size_align_unchecked(size, layout.align().max(GRANULE as usize)) }
+}
+
+pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+  let shadowed = shadowed_layout(layout);
+  let raw = checked_alloc(shadowed);
+
+  if raw.is_null() {
+    return raw;
+  }
+
+  let ptr = raw.add(left_redzone(layout));
+
+  // Blocks outside the heap, like whole page allocations, keep their redzones but have no shadow to mark them in.
+  if !in_heap(raw as u64) {
+    return ptr;
+  }
+
+  poison(raw as u64, shadowed.size() as u64, HEAP_REDZONE);
+  unpoison(ptr as u64, layout.size() as u64);
+
+  ptr
+}
+
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+  let shadowed = shadowed_layout(layout);
+  let raw = ptr.sub(left_redzone(layout));
+
+  if !in_heap(raw as u64) {
+    return checked_dealloc(raw, shadowed);
+  }
+
+  if shadow_value(raw as u64) != HEAP_REDZONE || shadow_value(ptr as u64) == FREED {
+    let kind = if shadow_value(ptr as u64) == FREED {
+      "double free"
+    } else {
+      "invalid free"
+    };
+
+    panic!(
+      "kasan: {} of {:#x} ({} bytes), shadow {:#x}",
+      kind,
+      ptr as u64,
+      layout.size(),
+      shadow_value(ptr as u64)
+    );
+  }
+
+  poison(raw as u64, shadowed.size() as u64, FREED);
+
+  let mut quarantine = QUARANTINE.lock();
+
+  quarantine.push(QuarantinedBlock {
+    raw: raw as usize,
+    size: shadowed.size(),
+    align: shadowed.align(),
+  });
+
+  while let Some(block) = quarantine.pop_over_limit() {
+    drop(quarantine);
+
+    poison(block.raw as u64, block.size as u64, UNALLOCATED);
+    checked_dealloc(block.raw as *mut u8, Layout::from_size_align_unchecked(block.size, block.align));
+
+    quarantine = QUARANTINE.lock();
+  }
+}
+
+// Reallocating a freed or overflowed block is reported before anything is copied out of it.
+pub unsafe fn realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+  let new = alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+
+  if !new.is_null() {
+    copy(ptr, new, layout.size().min(new_size));
+    dealloc(ptr, layout);
+  }
+
+  new
+}
+
+// Called with the heap lock held, hence the local flush.
+pub fn grow(end: u64) {
+  let heap_end = HEAP_END.load(Ordering::Acquire);
+
+  if SHADOW_START.load(Ordering::Relaxed) == 0 || end <= heap_end {
+    return;
+  }
+
+  let mapped_end = SHADOW_MAPPED_END.load(Ordering::Relaxed);
+  let shadow_end = align_up(shadow_for(end) as u64, 4096);
+
+  if shadow_end > mapped_end {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    map_new_pages(mapped_end, shadow_end, flags, false).expect("failed to map kasan shadow memory");
+
+    SHADOW_MAPPED_END.store(shadow_end, Ordering::Relaxed);
+  }
+
+  poison(heap_end, end - heap_end, UNALLOCATED);
+
+  HEAP_END.store(end, Ordering::Release);
+}
+
+// Called with the heap still empty, the shadow of the whole heap range is reserved up front.
+pub fn init(heap_start: u64, heap_size: u64) {
+  let shadow_start = vmm::allocate(Region::Vmalloc, HEAP_MAX_SIZE >> SHADOW_SCALE)
+    .expect("failed to reserve kasan shadow memory")
+    .as_u64();
+
+  HEAP_START.store(heap_start, Ordering::Relaxed);
+  HEAP_END.store(heap_start, Ordering::Release);
+  SHADOW_START.store(shadow_start, Ordering::Relaxed);
+  SHADOW_MAPPED_END.store(shadow_start, Ordering::Relaxed);
+
+  grow(heap_start + heap_size);
+
+  log::info!(
+    "kasan shadow for heap {:#x}..{:#x} at {:#x}",
+    heap_start,
+    heap_start + HEAP_MAX_SIZE,
+    shadow_start
+  );
+}
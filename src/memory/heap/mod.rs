@@ -0,0 +1,355 @@
+#[cfg(feature = "kasan")]
+pub mod kasan;
+#[cfg(feature = "heap-poison")]
+pub mod poison;
+mod slab;
+#[cfg(feature = "alloc-track")]
+pub mod tracker;
+
+pub use slab::SlabStats;
+
+use super::vmm::{self, Region};
+use crate::utils::{locked::IrqLocked, seqlock::SeqLocked};
+
+use core::{
+  alloc::{GlobalAlloc, Layout},
+  ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+use x86_64::{align_up, instructions::interrupts, structures::paging::PageTableFlags, VirtAddr};
+
+pub const HEAP_INITIAL_SIZE: u64 = 1024 * 1024;
+pub const HEAP_MAX_SIZE: u64 = 256 * 1024 * 1024;
+
+// The heap never grows by less than this, so that small allocations do not map pages one at a time.
+const HEAP_MIN_GROWTH: u64 = 256 * 1024;
+
+const MAX_DEFERRED_VFREES: usize = 64;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+static HEAP: IrqLocked<Heap> = IrqLocked::new(Heap::empty());
+
+// Kept apart from the heap, so that reading them never waits for an allocation.
+static STATS: SeqLocked<HeapStats> = SeqLocked::new(HeapStats {
+  size: 0,
+  used: 0,
+  high_water: 0,
+  limit: HEAP_MAX_SIZE as usize,
+  pages: 0,
+});
+
+// Page allocations freed with interrupts disabled, see `page_dealloc`.
+static DEFERRED_VFREES: IrqLocked<([usize; MAX_DEFERRED_VFREES], usize)> = IrqLocked::new(([0; MAX_DEFERRED_VFREES], 0));
+
+pub struct KernelAllocator;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+  pub size: usize,
+  pub used: usize,
+  pub high_water: usize,
+  pub limit: usize,
+  pub pages: usize,
+}
+
+fn update_stats(f: impl FnOnce(&mut HeapStats)) {
+  let mut stats = STATS.write();
+
+  f(&mut stats);
+  stats.high_water = stats.high_water.max(stats.used);
+}
+
+fn update_heap_stats(heap: &Heap) {
+  update_stats(|stats| {
+    stats.size = heap.size() + stats.pages;
+    stats.used = heap.used() + stats.pages;
+  });
+}
+
+fn update_page_stats(bytes: usize, allocated: bool) {
+  update_stats(|stats| {
+    if allocated {
+      stats.pages += bytes;
+      stats.size += bytes;
+      stats.used += bytes;
+    } else {
+      stats.pages -= bytes;
+      stats.size -= bytes;
+      stats.used -= bytes;
+    }
+  });
+}
+
+fn grow(heap: &mut Heap, layout: Layout) -> bool {
+  let needed = align_up((layout.size() + layout.align()) as u64, 4096).max(HEAP_MIN_GROWTH);
+  let size = heap.size() as u64;
+
+  if size + needed > HEAP_MAX_SIZE {
+    return false;
+  }
+
+  let top = heap.top() as u64;
+  let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+  if let Err(err) = super::map_new_pages(top, top + needed, flags, false) {
+    log::warn!("failed to grow the heap by {:#x} bytes: {:?}", needed, err);
+
+    return false;
+  }
+
+  unsafe {
+    heap.extend(needed as usize);
+  }
+
+  #[cfg(feature = "kasan")]
+  kasan::grow(heap.top() as u64);
+
+  log::debug!("grew the heap to {:#x} bytes", heap.size());
+
+  true
+}
+
+// Used for the slabs, and for allocations too large for the slab caches once the vmalloc region runs out of room.
+unsafe fn backing_alloc(layout: Layout) -> *mut u8 {
+  let mut heap = HEAP.lock();
+
+  loop {
+    if let Ok(allocation) = heap.allocate_first_fit(layout) {
+      update_heap_stats(&heap);
+
+      return allocation.as_ptr();
+    }
+
+    if !grow(&mut heap, layout) {
+      return ptr::null_mut();
+    }
+  }
+}
+
+unsafe fn backing_dealloc(ptr: *mut u8, layout: Layout) {
+  let mut heap = HEAP.lock();
+
+  heap.deallocate(NonNull::new_unchecked(ptr), layout);
+  update_heap_stats(&heap);
+}
+
+fn vfree_deferred() {
+  loop {
+    let addr = {
+      let mut deferred = DEFERRED_VFREES.lock();
+      let (addrs, count) = &mut *deferred;
+
+      if *count == 0 {
+        return;
+      }
+
+      *count -= 1;
+      addrs[*count]
+    };
+
+    vmm::vfree(VirtAddr::new(addr as u64));
+  }
+}
+
+fn page_bytes(layout: Layout) -> usize {
+  align_up(layout.size().max(1) as u64, 4096) as usize
+}
+
+unsafe fn page_alloc(layout: Layout) -> *mut u8 {
+  match vmm::vmalloc_aligned(layout.size() as u64, layout.align() as u64) {
+    Some(addr) => {
+      update_page_stats(page_bytes(layout), true);
+
+      addr.as_mut_ptr()
+    }
+    None => backing_alloc(layout),
+  }
+}
+
+// Unmapping needs a shootdown, which is never done with interrupts disabled, so such frees wait for the next one.
+unsafe fn page_dealloc(ptr: *mut u8, layout: Layout) {
+  if !Region::Vmalloc.contains(VirtAddr::new(ptr as u64)) {
+    return backing_dealloc(ptr, layout);
+  }
+
+  if !interrupts::are_enabled() {
+    let mut deferred = DEFERRED_VFREES.lock();
+    let (addrs, count) = &mut *deferred;
+
+    if *count < MAX_DEFERRED_VFREES {
+      addrs[*count] = ptr as usize;
+      *count += 1;
+
+      update_page_stats(page_bytes(layout), false);
+    } else {
+      log::warn!("leaking {:#x} bytes at {:#x}, too many deferred frees", layout.size(), ptr as u64);
+    }
+
+    return;
+  }
+
+  vfree_deferred();
+  vmm::vfree(VirtAddr::new(ptr as u64));
+
+  update_page_stats(page_bytes(layout), false);
+}
+
+unsafe fn raw_alloc(layout: Layout) -> *mut u8 {
+  match slab::allocate(layout) {
+    Some(ptr) => ptr,
+    None => page_alloc(layout),
+  }
+}
+
+unsafe fn raw_dealloc(ptr: *mut u8, layout: Layout) {
+  if !slab::deallocate(ptr, layout) {
+    page_dealloc(ptr, layout);
+  }
+}
+
+#[cfg(not(feature = "alloc-track"))]
+unsafe fn tracked_alloc(layout: Layout) -> *mut u8 {
+  raw_alloc(layout)
+}
+
+#[cfg(not(feature = "alloc-track"))]
+unsafe fn tracked_dealloc(ptr: *mut u8, layout: Layout) {
+  raw_dealloc(ptr, layout)
+}
+
+#[cfg(feature = "alloc-track")]
+unsafe fn tracked_alloc(layout: Layout) -> *mut u8 {
+  tracker::record_alloc(raw_alloc(tracker::tracked_layout(layout)), layout)
+}
+
+#[cfg(feature = "alloc-track")]
+unsafe fn tracked_dealloc(ptr: *mut u8, layout: Layout) {
+  raw_dealloc(tracker::record_dealloc(ptr, layout), tracker::tracked_layout(layout))
+}
+
+#[cfg(not(feature = "heap-poison"))]
+unsafe fn checked_alloc(layout: Layout) -> *mut u8 {
+  tracked_alloc(layout)
+}
+
+#[cfg(not(feature = "heap-poison"))]
+unsafe fn checked_dealloc(ptr: *mut u8, layout: Layout) {
+  tracked_dealloc(ptr, layout)
+}
+
+#[cfg(feature = "heap-poison")]
+unsafe fn checked_alloc(layout: Layout) -> *mut u8 {
+  poison::alloc(layout)
+}
+
+#[cfg(feature = "heap-poison")]
+unsafe fn checked_dealloc(ptr: *mut u8, layout: Layout) {
+  poison::dealloc(ptr, layout)
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+  #[cfg(not(feature = "kasan"))]
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    checked_alloc(layout)
+  }
+
+  #[cfg(not(feature = "kasan"))]
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    checked_dealloc(ptr, layout)
+  }
+
+  #[cfg(feature = "kasan")]
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    kasan::alloc(layout)
+  }
+
+  #[cfg(feature = "kasan")]
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    kasan::dealloc(ptr, layout)
+  }
+
+  #[cfg(feature = "kasan")]
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    kasan::realloc(ptr, layout, new_size)
+  }
+}
+
+pub fn slab_stats() -> impl Iterator<Item = SlabStats> {
+  slab::stats()
+}
+
+pub fn stats() -> HeapStats {
+  STATS.read()
+}
+
+pub fn check() -> bool {
+  let heap_range = {
+    let heap = HEAP.lock();
+
+    heap.bottom()..heap.top()
+  };
+
+  let broken_caches = slab::validate(heap_range);
+
+  #[cfg(feature = "heap-poison")]
+  let corrupted = poison::walk().corrupted;
+  #[cfg(not(feature = "heap-poison"))]
+  let corrupted = 0;
+
+  broken_caches == 0 && corrupted == 0
+}
+
+pub fn init() {
+  // The whole range the heap may grow into is reserved up front, only the start of it gets mapped.
+  let heap_start = vmm::allocate(Region::Heap, HEAP_MAX_SIZE).expect("failed to reserve the heap range").as_u64();
+
+  super::map_pages(
+    heap_start,
+    heap_start + HEAP_INITIAL_SIZE,
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    false,
+  )
+  .expect("failed to map heap pages");
+
+  let mut heap = HEAP.lock();
+
+  unsafe {
+    heap.init(heap_start as _, HEAP_INITIAL_SIZE as _);
+  }
+
+  update_heap_stats(&heap);
+  drop(heap);
+
+  #[cfg(feature = "kasan")]
+  kasan::init(heap_start, HEAP_INITIAL_SIZE);
+
+  log::info!(
+    "created heap of size {:#x} at {:#x}, growing up to {:#x}",
+    HEAP_INITIAL_SIZE,
+    heap_start,
+    HEAP_MAX_SIZE
+  );
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+  if !check() {
+    log::error!("the heap is corrupted, the allocation failure may be a consequence of it");
+  }
+
+  #[cfg(feature = "alloc-track")]
+  tracker::dump(10);
+
+  let stats = stats();
+
+  panic!(
+    "failed to allocate memory of size {:#x} and layout {:#x}, heap is {:#x} of {:#x} bytes with {:#x} used",
+    layout.size(),
+    layout.align(),
+    stats.size,
+    stats.limit,
+    stats.used
+  );
+}
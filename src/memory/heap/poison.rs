@@ -0,0 +1,214 @@
+use super::{tracked_alloc, tracked_dealloc};
+use crate::utils::locked::IrqLocked;
+
+use core::{
+  alloc::Layout,
+  mem::{align_of, size_of},
+  ptr,
+};
+
+pub const FRESH_POISON: u8 = 0xa5;
+pub const FREED_POISON: u8 = 0x6b;
+
+const HEAD_CANARY: u64 = 0x5afe_c0de_5afe_c0de;
+const TAIL_CANARY: u64 = 0xdead_beef_dead_beef;
+
+#[repr(C)]
+struct Header {
+  prev: *mut Header,
+  next: *mut Header,
+  size: usize,
+  canary: u64,
+}
+
+struct LiveBlocks {
+  head: *mut Header,
+  count: usize,
+  bytes: usize,
+}
+
+unsafe impl Send for LiveBlocks {}
+
+static LIVE: IrqLocked<LiveBlocks> = IrqLocked::new(LiveBlocks {
+  head: ptr::null_mut(),
+  count: 0,
+  bytes: 0,
+});
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WalkStats {
+  pub blocks: usize,
+  pub bytes: usize,
+  pub corrupted: usize,
+}
+
+fn header_size(layout: Layout) -> usize {
+  layout.align().max(size_of::<Header>())
+}
+
+fn poisoned_layout(layout: Layout) -> Layout {
+  let size = header_size(layout) + layout.size() + size_of::<u64>();
+
+  unsafe { Layout::from_size_align_unchecked(size, layout.align().max(align_of::<Header>())) }
+}
+
+unsafe fn header_of(ptr: *mut u8) -> *mut Header {
+  (ptr as *mut Header).sub(1)
+}
+
+unsafe fn data_of(header: *mut Header) -> *mut u8 {
+  header.add(1) as *mut u8
+}
+
+unsafe fn tail_of(header: *mut Header) -> *mut u64 {
+  data_of(header).add((*header).size) as *mut u64
+}
+
+unsafe fn corruption(header: *mut Header) -> Option<&'static str> {
+  if (*header).canary != HEAD_CANARY {
+    Some("head canary overwritten")
+  } else if tail_of(header).read_unaligned() != TAIL_CANARY {
+    Some("tail canary overwritten")
+  } else {
+    None
+  }
+}
+
+pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+  let block = tracked_alloc(poisoned_layout(layout));
+
+  if block.is_null() {
+    return block;
+  }
+
+  let ptr = block.add(header_size(layout));
+  let header = header_of(ptr);
+
+  header.write(Header {
+    prev: ptr::null_mut(),
+    next: ptr::null_mut(),
+    size: layout.size(),
+    canary: HEAD_CANARY,
+  });
+
+  ptr::write_bytes(ptr, FRESH_POISON, layout.size());
+  tail_of(header).write_unaligned(TAIL_CANARY);
+
+  let mut live = LIVE.lock();
+
+  (*header).next = live.head;
+
+  if !live.head.is_null() {
+    (*live.head).prev = header;
+  }
+
+  live.head = header;
+  live.count += 1;
+  live.bytes += layout.size();
+
+  ptr
+}
+
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+  let header = header_of(ptr);
+  let mut live = LIVE.lock();
+
+  if let Some(problem) = corruption(header) {
+    drop(live);
+
+    panic!("heap corruption in block {:#x} of {} bytes: {}", ptr as u64, layout.size(), problem);
+  }
+
+  if (*header).size != layout.size() {
+    let size = (*header).size;
+
+    drop(live);
+
+    panic!(
+      "block {:#x} freed with size {} but allocated with size {}",
+      ptr as u64,
+      layout.size(),
+      size
+    );
+  }
+
+  let (prev, next) = ((*header).prev, (*header).next);
+
+  if prev.is_null() {
+    live.head = next;
+  } else {
+    (*prev).next = next;
+  }
+
+  if !next.is_null() {
+    (*next).prev = prev;
+  }
+
+  live.count -= 1;
+  live.bytes -= layout.size();
+
+  drop(live);
+
+  let poisoned = poisoned_layout(layout);
+  let block = ptr.sub(header_size(layout));
+
+  ptr::write_bytes(block, FREED_POISON, poisoned.size());
+
+  tracked_dealloc(block, poisoned);
+}
+
+pub fn walk() -> WalkStats {
+  let live = LIVE.lock();
+  let mut stats = WalkStats::default();
+  let mut prev = ptr::null_mut();
+  let mut header = live.head;
+
+  unsafe {
+    while !header.is_null() {
+      if stats.blocks == live.count {
+        log::error!("heap walk: live block list is longer than the {} blocks recorded", live.count);
+
+        stats.corrupted += 1;
+        break;
+      }
+
+      if header as usize % align_of::<Header>() != 0 || (*header).prev != prev {
+        log::error!("heap walk: broken link to {:#x} after {:#x}", header as u64, prev as u64);
+
+        stats.corrupted += 1;
+        break;
+      }
+
+      if let Some(problem) = corruption(header) {
+        log::error!(
+          "heap walk: block {:#x} of {} bytes: {}",
+          data_of(header) as u64,
+          (*header).size,
+          problem
+        );
+
+        stats.corrupted += 1;
+      }
+
+      stats.blocks += 1;
+      stats.bytes += (*header).size;
+
+      prev = header;
+      header = (*header).next;
+    }
+  }
+
+  if stats.corrupted == 0 && (stats.blocks != live.count || stats.bytes != live.bytes) {
+    log::error!(
+      "heap walk: found {} blocks with {:#x} bytes, expected {} with {:#x}",
+      stats.blocks,
+      stats.bytes,
+      live.count,
+      live.bytes
+    );
+
+    stats.corrupted += 1;
+  }
+
+  stats
+}
@@ -0,0 +1,194 @@
+use super::backing_alloc;
+use crate::utils::locked::IrqLocked;
+
+use core::{alloc::Layout, ops::Range, ptr};
+
+const SLAB_SIZE: usize = 4096;
+
+pub const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct FreeObject {
+  next: *mut FreeObject,
+}
+
+// TODO: Put per-cpu magazines in front of the caches once the other cpus are brought up
+struct SlabCache {
+  object_size: usize,
+  free_list: *mut FreeObject,
+  slabs: usize,
+  in_use: usize,
+  free: usize,
+  allocations: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SlabStats {
+  pub object_size: usize,
+  pub slabs: usize,
+  pub in_use: usize,
+  pub free: usize,
+  pub allocations: u64,
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+  const fn new(object_size: usize) -> Self {
+    Self {
+      object_size,
+      free_list: ptr::null_mut(),
+      slabs: 0,
+      in_use: 0,
+      free: 0,
+      allocations: 0,
+    }
+  }
+
+  unsafe fn refill(&mut self) -> bool {
+    let slab = backing_alloc(Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE));
+
+    if slab.is_null() {
+      return false;
+    }
+
+    for index in (0..SLAB_SIZE / self.object_size).rev() {
+      let object = slab.add(index * self.object_size) as *mut FreeObject;
+
+      (*object).next = self.free_list;
+      self.free_list = object;
+    }
+
+    self.slabs += 1;
+    self.free += SLAB_SIZE / self.object_size;
+
+    true
+  }
+
+  unsafe fn allocate(&mut self) -> *mut u8 {
+    if self.free_list.is_null() && !self.refill() {
+      return ptr::null_mut();
+    }
+
+    let object = self.free_list;
+
+    self.free_list = (*object).next;
+    self.free -= 1;
+    self.in_use += 1;
+    self.allocations += 1;
+
+    object as *mut u8
+  }
+
+  unsafe fn deallocate(&mut self, ptr: *mut u8) {
+    let object = ptr as *mut FreeObject;
+
+    (*object).next = self.free_list;
+    self.free_list = object;
+    self.free += 1;
+    self.in_use -= 1;
+  }
+
+  fn validate(&self, heap: &Range<usize>) -> bool {
+    let mut object = self.free_list;
+    let mut count = 0;
+
+    while !object.is_null() {
+      if count == self.free {
+        log::error!(
+          "slab cache {}: free list is longer than its {} free objects",
+          self.object_size,
+          self.free
+        );
+
+        return false;
+      }
+
+      if !heap.contains(&(object as usize)) || object as usize % self.object_size != 0 {
+        log::error!("slab cache {}: bad free object {:#x}", self.object_size, object as u64);
+
+        return false;
+      }
+
+      count += 1;
+      object = unsafe { (*object).next };
+    }
+
+    if count != self.free {
+      log::error!(
+        "slab cache {}: found {} free objects, expected {}",
+        self.object_size,
+        count,
+        self.free
+      );
+
+      return false;
+    }
+
+    if self.in_use + self.free != self.slabs * (SLAB_SIZE / self.object_size) {
+      log::error!(
+        "slab cache {}: {} objects in use and {} free do not fill {} slabs",
+        self.object_size,
+        self.in_use,
+        self.free,
+        self.slabs
+      );
+
+      return false;
+    }
+
+    true
+  }
+
+  fn stats(&self) -> SlabStats {
+    SlabStats {
+      object_size: self.object_size,
+      slabs: self.slabs,
+      in_use: self.in_use,
+      free: self.free,
+      allocations: self.allocations,
+    }
+  }
+}
+
+static CACHES: [IrqLocked<SlabCache>; 8] = [
+  IrqLocked::new(SlabCache::new(SIZE_CLASSES[0])),
+  IrqLocked::new(SlabCache::new(SIZE_CLASSES[1])),
+  IrqLocked::new(SlabCache::new(SIZE_CLASSES[2])),
+  IrqLocked::new(SlabCache::new(SIZE_CLASSES[3])),
+  IrqLocked::new(SlabCache::new(SIZE_CLASSES[4])),
+  IrqLocked::new(SlabCache::new(SIZE_CLASSES[5])),
+  IrqLocked::new(SlabCache::new(SIZE_CLASSES[6])),
+  IrqLocked::new(SlabCache::new(SIZE_CLASSES[7])),
+];
+
+// Objects are aligned to their size class, since slabs are page aligned and classes are powers of two.
+fn cache_for(layout: Layout) -> Option<&'static IrqLocked<SlabCache>> {
+  let size = layout.size().max(layout.align());
+
+  SIZE_CLASSES
+    .iter()
+    .position(|&class| size <= class)
+    .map(|index| &CACHES[index])
+}
+
+pub unsafe fn allocate(layout: Layout) -> Option<*mut u8> {
+  cache_for(layout).map(|cache| cache.lock().allocate())
+}
+
+pub unsafe fn deallocate(ptr: *mut u8, layout: Layout) -> bool {
+  match cache_for(layout) {
+    Some(cache) => {
+      cache.lock().deallocate(ptr);
+      true
+    }
+    None => false,
+  }
+}
+
+pub fn validate(heap: Range<usize>) -> usize {
+  CACHES.iter().filter(|cache| !cache.lock().validate(&heap)).count()
+}
+
+pub fn stats() -> impl Iterator<Item = SlabStats> {
+  CACHES.iter().map(|cache| cache.lock().stats())
+}
@@ -0,0 +1,247 @@
+use crate::utils::{
+  locked::IrqLocked,
+  symbols::{self, ReturnAddresses},
+};
+
+use core::{
+  alloc::Layout,
+  fmt::{self, Write},
+  sync::atomic::{AtomicU32, Ordering},
+};
+use rustc_demangle::Demangle;
+
+const MAX_SITES: usize = 512;
+const SITE_FRAMES: usize = 8;
+
+// Frames belonging to these are the allocator itself, not the code that asked for memory.
+const ALLOCATOR_SYMBOLS: [&str; 5] = ["__rust_alloc", "__rg_alloc", "__rust_dealloc", "alloc::", "kernel::memory::heap::"];
+
+// Every tracked allocation is preceded by one of these, padded up to the alignment of the allocation.
+#[repr(C)]
+struct Header {
+  site: u32,
+  generation: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Site {
+  frames: [u64; SITE_FRAMES],
+  live_count: usize,
+  live_bytes: usize,
+  total_count: u64,
+  checkpoint_count: usize,
+  checkpoint_bytes: usize,
+}
+
+struct Sites {
+  sites: [Site; MAX_SITES],
+  count: usize,
+  untracked: u64,
+}
+
+const EMPTY_SITE: Site = Site {
+  frames: [0; SITE_FRAMES],
+  live_count: 0,
+  live_bytes: 0,
+  total_count: 0,
+  checkpoint_count: 0,
+  checkpoint_bytes: 0,
+};
+
+// Allocations made after a checkpoint carry a generation at least as large as it.
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+static SITES: IrqLocked<Sites> = IrqLocked::new(Sites {
+  sites: [EMPTY_SITE; MAX_SITES],
+  count: 0,
+  untracked: 0,
+});
+
+fn header_size(layout: Layout) -> usize {
+  layout.align().max(core::mem::size_of::<Header>())
+}
+
+pub fn tracked_layout(layout: Layout) -> Layout {
+  unsafe { Layout::from_size_align_unchecked(layout.size() + header_size(layout), layout.align().max(core::mem::align_of::<Header>())) }
+}
+
+#[inline(always)]
+fn caller_frames() -> [u64; SITE_FRAMES] {
+  let mut frames = [0; SITE_FRAMES];
+
+  if let Some(return_addrs) = ReturnAddresses::current() {
+    for (frame, return_addr) in frames.iter_mut().zip(return_addrs) {
+      *frame = return_addr;
+    }
+  }
+
+  frames
+}
+
+impl Sites {
+  fn find_or_insert(&mut self, frames: [u64; SITE_FRAMES]) -> Option<usize> {
+    if let Some(index) = self.sites[..self.count].iter().position(|site| site.frames == frames) {
+      return Some(index);
+    }
+
+    if self.count == MAX_SITES {
+      return None;
+    }
+
+    self.sites[self.count] = Site { frames, ..EMPTY_SITE };
+    self.count += 1;
+
+    Some(self.count - 1)
+  }
+}
+
+#[inline(always)]
+pub unsafe fn record_alloc(block: *mut u8, layout: Layout) -> *mut u8 {
+  if block.is_null() {
+    return block;
+  }
+
+  let frames = caller_frames();
+  let mut sites = SITES.lock();
+  let generation = GENERATION.load(Ordering::Relaxed);
+
+  let site = match sites.find_or_insert(frames) {
+    Some(index) => {
+      let site = &mut sites.sites[index];
+
+      site.live_count += 1;
+      site.live_bytes += layout.size();
+      site.total_count += 1;
+      site.checkpoint_count += 1;
+      site.checkpoint_bytes += layout.size();
+
+      index as u32
+    }
+    None => {
+      sites.untracked += 1;
+
+      u32::MAX
+    }
+  };
+
+  let ptr = block.add(header_size(layout));
+
+  (ptr as *mut Header).sub(1).write(Header { site, generation });
+
+  ptr
+}
+
+pub unsafe fn record_dealloc(ptr: *mut u8, layout: Layout) -> *mut u8 {
+  let header = (ptr as *mut Header).sub(1).read();
+  let mut sites = SITES.lock();
+
+  if let Some(site) = sites.sites.get_mut(header.site as usize) {
+    site.live_count -= 1;
+    site.live_bytes -= layout.size();
+
+    if header.generation >= GENERATION.load(Ordering::Relaxed) {
+      site.checkpoint_count -= 1;
+      site.checkpoint_bytes -= layout.size();
+    }
+  }
+
+  ptr.sub(header_size(layout))
+}
+
+// Starts a new leak window: `dump_leaks` only reports allocations made after the latest checkpoint.
+pub fn checkpoint() {
+  let mut sites = SITES.lock();
+  let count = sites.count;
+
+  GENERATION.fetch_add(1, Ordering::Relaxed);
+
+  for site in &mut sites.sites[..count] {
+    site.checkpoint_count = 0;
+    site.checkpoint_bytes = 0;
+  }
+}
+
+struct NamePrefix {
+  buffer: [u8; 64],
+  len: usize,
+}
+
+impl Write for NamePrefix {
+  fn write_str(&mut self, string: &str) -> fmt::Result {
+    let count = string.len().min(self.buffer.len() - self.len);
+
+    self.buffer[self.len..self.len + count].copy_from_slice(&string.as_bytes()[..count]);
+    self.len += count;
+
+    Ok(())
+  }
+}
+
+fn is_allocator_symbol(name: &Demangle) -> bool {
+  let mut prefix = NamePrefix { buffer: [0; 64], len: 0 };
+  let _ = write!(prefix, "{:#}", name);
+
+  ALLOCATOR_SYMBOLS
+    .iter()
+    .any(|symbol| prefix.buffer[..prefix.len].starts_with(symbol.as_bytes()))
+}
+
+fn log_site(site: &Site, count: usize, bytes: usize) {
+  log::info!("{:#x} bytes in {} allocations ({} made in total)", bytes, count, site.total_count);
+
+  let callers = site.frames.iter().filter(|&&frame| frame != 0).filter_map(|&frame| {
+    let name = symbols::lookup(frame)?;
+
+    if is_allocator_symbol(&name) {
+      None
+    } else {
+      Some((frame, name))
+    }
+  });
+
+  for (frame, name) in callers {
+    log::info!("  {:#x}: {}", frame, name);
+  }
+}
+
+fn dump_by<F: Fn(&Site) -> (usize, usize)>(top: usize, key: F) {
+  let sites = SITES.lock();
+  let mut last = None;
+
+  // Picks the next largest site on every pass, so nothing has to be allocated or sorted in place.
+  for _ in 0..top {
+    let next = sites.sites[..sites.count]
+      .iter()
+      .enumerate()
+      .map(|(index, site)| (key(site).1, index))
+      .filter(|&(bytes, _)| bytes > 0)
+      .filter(|&rank| last.map_or(true, |last| rank < last))
+      .max();
+
+    match next {
+      Some((bytes, index)) => {
+        let site = &sites.sites[index];
+
+        log_site(site, key(site).0, bytes);
+
+        last = Some((bytes, index));
+      }
+      None => break,
+    }
+  }
+
+  if sites.untracked > 0 {
+    log::warn!("{} allocations could not be attributed, the site table is full", sites.untracked);
+  }
+}
+
+pub fn dump(top: usize) {
+  log::info!("top {} heap consumers by live bytes:", top);
+
+  dump_by(top, |site| (site.live_count, site.live_bytes));
+}
+
+pub fn dump_leaks(top: usize) {
+  log::info!("allocations still live since the last checkpoint:");
+
+  dump_by(top, |site| (site.checkpoint_count, site.checkpoint_bytes));
+}
@@ -0,0 +1,130 @@
+use super::{
+  frame_allocator::Zone,
+  heap::{self, HeapStats},
+  memmap::{self, MapReport},
+  page_table,
+  vmm::{self, Region},
+  FRAME_ALLOC, MAPPER, MEMORY_REGIONS,
+};
+
+use bootloader::boot_info::MemoryRegionKind;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemInfo {
+  pub map: MapReport,
+  pub usable: u64,
+  pub bootloader: u64,
+  pub uefi: u64,
+  pub bios: u64,
+  pub other: u64,
+  pub managed: u64,
+  pub free: u64,
+  pub zones: [(u64, u64); 3],
+  pub page_tables: u64,
+  pub regions: [u64; 5],
+  pub heap: HeapStats,
+}
+
+pub fn meminfo() -> MemInfo {
+  let mut info = MemInfo::default();
+
+  for region in MEMORY_REGIONS.get().expect("memory regions are unknown").iter() {
+    let size = region.end - region.start;
+
+    match region.kind {
+      MemoryRegionKind::Usable => info.usable += size,
+      MemoryRegionKind::Bootloader => info.bootloader += size,
+      MemoryRegionKind::UnknownUefi(_) => info.uefi += size,
+      MemoryRegionKind::UnknownBios(_) => info.bios += size,
+      _ => info.other += size,
+    }
+  }
+
+  {
+    let frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+    info.managed = frame_alloc.total_frames() as u64 * 4096;
+    info.free = frame_alloc.free_frames() as u64 * 4096;
+
+    for &zone in Zone::ALL.iter() {
+      let (total, free) = frame_alloc.zone_frames(zone);
+
+      info.zones[zone as usize] = (total as u64 * 4096, free as u64 * 4096);
+    }
+  }
+
+  {
+    let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+    let phys_mem_offset = mapper.phys_offset();
+
+    info.page_tables = page_table::count_tables(mapper.level_4_table(), phys_mem_offset) as u64 * 4096;
+  }
+
+  for &region in Region::ALL.iter() {
+    info.regions[region as usize] = vmm::used(region);
+  }
+
+  info.map = memmap::report();
+  info.heap = heap::stats();
+  info
+}
+
+pub fn log() {
+  let info = meminfo();
+  let kib = |bytes: u64| bytes / 1024;
+
+  log::info!(
+    "memory map: {} regions covering {} KiB, highest address {:#x}, {} unsorted, {} overlapping",
+    info.map.regions,
+    kib(info.map.total),
+    info.map.highest,
+    info.map.unsorted_count,
+    info.map.overlap_count
+  );
+  log::info!(
+    "memory: {} KiB usable, {} KiB bootloader, {} KiB uefi, {} KiB bios, {} KiB other",
+    kib(info.usable),
+    kib(info.bootloader),
+    kib(info.uefi),
+    kib(info.bios),
+    kib(info.other)
+  );
+  log::info!(
+    "frames: {} KiB managed, {} KiB allocated, {} KiB free",
+    kib(info.managed),
+    kib(info.managed - info.free),
+    kib(info.free)
+  );
+
+  for &zone in Zone::ALL.iter() {
+    let (total, free) = info.zones[zone as usize];
+
+    log::info!("zone {:?}: {} KiB, {} KiB free", zone, kib(total), kib(free));
+  }
+
+  log::info!("page tables: {} KiB", kib(info.page_tables));
+
+  for &region in Region::ALL.iter() {
+    log::info!("region {:?}: {} KiB in use", region, kib(info.regions[region as usize]));
+  }
+
+  log::info!(
+    "heap: {} KiB mapped, {} KiB used, {} KiB peak, {} KiB limit, {} KiB in whole pages",
+    kib(info.heap.size as u64),
+    kib(info.heap.used as u64),
+    kib(info.heap.high_water as u64),
+    kib(info.heap.limit as u64),
+    kib(info.heap.pages as u64)
+  );
+
+  for cache in heap::slab_stats() {
+    log::debug!(
+      "slab {}: {} slabs, {} in use, {} free, {} allocations",
+      cache.object_size,
+      cache.slabs,
+      cache.in_use,
+      cache.free,
+      cache.allocations
+    );
+  }
+}
@@ -0,0 +1,166 @@
+use super::MEMORY_REGIONS;
+
+use bootloader::boot_info::{MemoryRegion, MemoryRegionKind};
+use core::fmt;
+use spin::Once;
+
+const UEFI_KINDS: [&str; 15] = [
+  "reserved",
+  "loader code",
+  "loader data",
+  "boot services code",
+  "boot services data",
+  "runtime services code",
+  "runtime services data",
+  "conventional",
+  "unusable",
+  "acpi reclaimable",
+  "acpi nvs",
+  "mmio",
+  "mmio port space",
+  "pal code",
+  "persistent",
+];
+
+const BIOS_KINDS: [&str; 5] = ["usable", "reserved", "acpi reclaimable", "acpi nvs", "bad memory"];
+
+// Anomalies beyond this many of a kind are only counted.
+pub const MAX_ANOMALIES: usize = 16;
+
+static REPORT: Once<MapReport> = Once::new();
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapReport {
+  pub regions: usize,
+  pub total: u64,
+  pub highest: u64,
+  pub unsorted_count: usize,
+  pub overlap_count: usize,
+  unsorted: [usize; MAX_ANOMALIES],
+  overlaps: [(usize, usize); MAX_ANOMALIES],
+}
+
+impl MapReport {
+  pub fn unsorted(&self) -> &[usize] {
+    &self.unsorted[..self.unsorted_count.min(MAX_ANOMALIES)]
+  }
+
+  pub fn overlaps(&self) -> &[(usize, usize)] {
+    &self.overlaps[..self.overlap_count.min(MAX_ANOMALIES)]
+  }
+}
+
+pub struct KindName(pub MemoryRegionKind);
+
+impl fmt::Display for KindName {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.0 {
+      MemoryRegionKind::Usable => write!(f, "usable"),
+      MemoryRegionKind::Bootloader => write!(f, "bootloader"),
+      MemoryRegionKind::UnknownUefi(kind) => match UEFI_KINDS.get(kind as usize) {
+        Some(name) => write!(f, "uefi {}", name),
+        None => write!(f, "uefi type {:#x}", kind),
+      },
+      MemoryRegionKind::UnknownBios(kind) => match (kind as usize).checked_sub(1).and_then(|index| BIOS_KINDS.get(index)) {
+        Some(name) => write!(f, "bios {}", name),
+        None => write!(f, "bios type {:#x}", kind),
+      },
+      kind => write!(f, "{:?}", kind),
+    }
+  }
+}
+
+fn regions() -> &'static [MemoryRegion] {
+  MEMORY_REGIONS.get().expect("memory regions are unknown")
+}
+
+fn overlap(a: &MemoryRegion, b: &MemoryRegion) -> bool {
+  a.start < b.end && b.start < a.end
+}
+
+fn analyze(regions: &[MemoryRegion]) -> MapReport {
+  let mut report = MapReport {
+    regions: regions.len(),
+    ..MapReport::default()
+  };
+
+  for (index, region) in regions.iter().enumerate() {
+    report.total += region.end.saturating_sub(region.start);
+    report.highest = report.highest.max(region.end);
+
+    if index > 0 && region.start < regions[index - 1].start {
+      log::warn!(
+        "memory map: region {} at {:#x} starts below region {} at {:#x}",
+        index,
+        region.start,
+        index - 1,
+        regions[index - 1].start
+      );
+
+      if let Some(slot) = report.unsorted.get_mut(report.unsorted_count) {
+        *slot = index;
+      }
+
+      report.unsorted_count += 1;
+    }
+
+    for (other_index, other) in regions[index + 1..].iter().enumerate() {
+      if overlap(region, other) {
+        let other_index = index + 1 + other_index;
+
+        log::warn!(
+          "memory map: region {} ({:#x}..{:#x}, {}) overlaps region {} ({:#x}..{:#x}, {})",
+          index,
+          region.start,
+          region.end,
+          KindName(region.kind),
+          other_index,
+          other.start,
+          other.end,
+          KindName(other.kind)
+        );
+
+        if let Some(slot) = report.overlaps.get_mut(report.overlap_count) {
+          *slot = (index, other_index);
+        }
+
+        report.overlap_count += 1;
+      }
+    }
+  }
+
+  report
+}
+
+pub fn report() -> MapReport {
+  *REPORT.get().expect("memory map has not been analyzed")
+}
+
+pub fn log() {
+  log::info!("memory map:");
+
+  for (index, region) in regions().iter().enumerate() {
+    log::info!(
+      "  {:>3}: {:#018x}..{:#018x} {:>10} KiB  {}",
+      index,
+      region.start,
+      region.end,
+      region.end.saturating_sub(region.start) / 1024,
+      KindName(region.kind)
+    );
+  }
+}
+
+pub fn init() {
+  log();
+
+  let report = REPORT.call_once(|| analyze(regions()));
+
+  for &index in report.unsorted() {
+    log::warn!("memory map region {} starts below the one before it", index);
+  }
+
+  for &(first, second) in report.overlaps() {
+    log::warn!("memory map regions {} and {} overlap", first, second);
+  }
+}
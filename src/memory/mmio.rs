@@ -0,0 +1,173 @@
+use super::{
+  map_physical_pages, unmap_physical_pages,
+  vmm::{self, Region},
+};
+
+use core::marker::PhantomData;
+use x86_64::{
+  align_down, align_up,
+  instructions::tlb,
+  registers::model_specific::Msr,
+  structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
+  PhysAddr, VirtAddr,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+// Memory types as encoded in the pat msr.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+
+// PAT stays clear so 4 KiB and huge pages agree, PCD alone now selects write-combining.
+const PAT_ENTRIES: [u64; 4] = [PAT_WB, PAT_WT, PAT_WC, PAT_UC];
+
+#[derive(Debug)]
+pub enum MmioError {
+  NoAddressSpace,
+  Map(MapToError<Size4KiB>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+  WriteBack,
+  WriteThrough,
+  WriteCombining,
+  Uncached,
+}
+
+impl CacheMode {
+  pub fn page_flags(self) -> PageTableFlags {
+    match self {
+      CacheMode::WriteBack => PageTableFlags::empty(),
+      CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+      CacheMode::WriteCombining => PageTableFlags::NO_CACHE,
+      CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+    }
+  }
+}
+
+pub fn init_pat() {
+  let pat = PAT_ENTRIES
+    .iter()
+    .chain(PAT_ENTRIES.iter())
+    .enumerate()
+    .fold(0_u64, |pat, (index, &entry)| pat | entry << (index * 8));
+
+  unsafe {
+    Msr::new(IA32_PAT).write(pat);
+
+    asm!("wbinvd", options(nostack, preserves_flags));
+  }
+
+  tlb::flush_all();
+
+  log::info!("programmed page attribute table with {:#018x}", pat);
+}
+
+pub struct MmioRegion {
+  virt: VirtAddr,
+  phys: PhysAddr,
+  size: usize,
+}
+
+impl MmioRegion {
+  pub fn virt_addr(&self) -> VirtAddr {
+    self.virt
+  }
+
+  pub fn phys_addr(&self) -> PhysAddr {
+    self.phys
+  }
+
+  pub fn len(&self) -> usize {
+    self.size
+  }
+
+  fn ptr<T>(&self, offset: usize) -> *mut T {
+    assert!(
+      offset + core::mem::size_of::<T>() <= self.size,
+      "mmio access at offset {:#x} is outside of the {:#x} byte region",
+      offset,
+      self.size
+    );
+
+    (self.virt + offset).as_mut_ptr()
+  }
+
+  pub fn read<T: Copy>(&self, offset: usize) -> T {
+    unsafe { core::ptr::read_volatile(self.ptr(offset)) }
+  }
+
+  pub fn write<T: Copy>(&self, offset: usize, value: T) {
+    unsafe { core::ptr::write_volatile(self.ptr(offset), value) }
+  }
+
+  pub fn register<T: Copy>(&self, offset: usize) -> Register<T> {
+    Register {
+      ptr: self.ptr(offset),
+      _region: PhantomData,
+    }
+  }
+}
+
+impl Drop for MmioRegion {
+  fn drop(&mut self) {
+    let start = VirtAddr::new(align_down(self.virt.as_u64(), 4096));
+    let length = vmm::free(Region::Mmio, start).expect("mmio region was not allocated from the mmio range");
+
+    unmap_physical_pages(start.as_u64(), start.as_u64() + length, false).expect("failed to unmap mmio region");
+  }
+}
+
+pub struct Register<'a, T: Copy> {
+  ptr: *mut T,
+  _region: PhantomData<&'a MmioRegion>,
+}
+
+impl<'a, T: Copy> Register<'a, T> {
+  pub fn read(&self) -> T {
+    unsafe { core::ptr::read_volatile(self.ptr) }
+  }
+
+  pub fn write(&self, value: T) {
+    unsafe { core::ptr::write_volatile(self.ptr, value) }
+  }
+
+  pub fn update<F: FnOnce(T) -> T>(&self, f: F) {
+    self.write(f(self.read()));
+  }
+}
+
+unsafe impl Send for MmioRegion {}
+unsafe impl Sync for MmioRegion {}
+
+pub fn ioremap(phys: PhysAddr, size: usize, mode: CacheMode) -> Result<MmioRegion, MmioError> {
+  let phys_start = align_down(phys.as_u64(), 4096);
+  let phys_end = align_up(phys.as_u64() + size as u64, 4096);
+  let length = phys_end - phys_start;
+
+  let virt_start = vmm::allocate(Region::Mmio, length).ok_or(MmioError::NoAddressSpace)?.as_u64();
+  let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | mode.page_flags();
+
+  if let Err(err) = map_physical_pages(virt_start, virt_start + length, phys_start, flags, false) {
+    vmm::free(Region::Mmio, VirtAddr::new(virt_start));
+
+    return Err(MmioError::Map(err));
+  }
+
+  log::debug!(
+    "mapped mmio region {:#x}..{:#x} at {:#x} as {:?}",
+    phys_start,
+    phys_end,
+    virt_start,
+    mode
+  );
+
+  Ok(MmioRegion {
+    virt: VirtAddr::new(virt_start + phys.as_u64() - phys_start),
+    phys,
+    size,
+  })
+}
@@ -1,18 +1,40 @@
+pub mod address_space;
+pub mod dma;
 mod frame_allocator;
-mod heap;
+pub mod heap;
+pub mod meminfo;
+pub mod memmap;
+pub mod mmio;
+mod page_table;
+mod protection;
+pub mod stack;
+pub mod tlb;
+pub mod vma;
+pub mod vmm;
 
-use crate::utils::locked::Locked;
+use crate::{
+  cpu,
+  utils::{locked::IrqLocked, ticket::IrqTicketLocked},
+  PHYS_MEM_OFFSET,
+};
 
 use bootloader::boot_info::MemoryRegions;
-use frame_allocator::GlobalFrameAllocator;
+use frame_allocator::{DeferredFrames, GlobalFrameAllocator, Zone, DEFERRED_RUNS};
 use spin::Once;
 use x86_64::{
-  structures::paging::{mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB},
+  align_down,
+  structures::paging::{
+    mapper::{FlagUpdateError, MapToError, TranslateResult, UnmapError},
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+    Size2MiB, Size4KiB, Translate,
+  },
   PhysAddr, VirtAddr,
 };
 
-pub static FRAME_ALLOC: Once<Locked<GlobalFrameAllocator>> = Once::new();
-pub static MAPPER: Once<Locked<OffsetPageTable>> = Once::new();
+// Taken by every cpu for each page it maps, a fair lock keeps any of them from starving.
+pub static FRAME_ALLOC: Once<IrqTicketLocked<GlobalFrameAllocator>> = Once::new();
+pub static MAPPER: Once<IrqLocked<OffsetPageTable>> = Once::new();
+pub static MEMORY_REGIONS: Once<&'static MemoryRegions> = Once::new();
 
 fn active_l4_table(phys_mem_offset: VirtAddr) -> &'static mut PageTable {
   use x86_64::registers::control::Cr3;
@@ -26,71 +48,411 @@ fn active_l4_table(phys_mem_offset: VirtAddr) -> &'static mut PageTable {
   unsafe { &mut *page_table_ptr }
 }
 
+fn page_range(start_addr: u64, end_addr: u64, inclusive: bool) -> (u64, u64) {
+  let start_page: Page = Page::containing_address(VirtAddr::new(start_addr));
+  let end_page: Page = Page::containing_address(VirtAddr::new(end_addr));
+  let end_page = if inclusive { end_page + 1 } else { end_page };
+
+  (start_page.start_address().as_u64(), end_page.start_address().as_u64())
+}
+
+fn huge_page_fits(addr: u64, phys: u64, end: u64, size: u64) -> bool {
+  addr % size == 0 && phys % size == 0 && end - addr >= size
+}
+
+fn into_4kib_error<S: PageSize>(err: MapToError<S>) -> MapToError<Size4KiB> {
+  match err {
+    MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+    MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+    MapToError::PageAlreadyMapped(frame) => MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address())),
+  }
+}
+
+fn map_page<S: PageSize>(
+  mapper: &mut OffsetPageTable<'static>,
+  frame_alloc: &mut GlobalFrameAllocator,
+  addr: u64,
+  phys: u64,
+  page_flags: PageTableFlags,
+) -> Result<(), MapToError<S>>
+where
+  OffsetPageTable<'static>: Mapper<S>,
+{
+  let page = Page::<S>::from_start_address(VirtAddr::new(addr)).expect("unaligned page");
+  let frame = PhysFrame::<S>::from_start_address(PhysAddr::new(phys)).expect("unaligned frame");
+
+  unsafe { mapper.map_to(page, frame, page_flags, frame_alloc)?.ignore() }
+
+  log::trace!("mapped virtual page {:#x} to physical frame {:#x} ({:#x} bytes)", addr, phys, S::SIZE);
+
+  Ok(())
+}
+
+fn split_huge_pages(
+  mapper: &mut OffsetPageTable<'static>,
+  frame_alloc: &mut GlobalFrameAllocator,
+  addr: u64,
+  size: u64,
+) -> Result<(), MapToError<Size4KiB>> {
+  while mapped_size(mapper, addr) > size {
+    let phys_mem_offset = mapper.phys_offset();
+
+    page_table::split_huge_page(mapper.level_4_table(), phys_mem_offset, VirtAddr::new(addr), frame_alloc)?;
+  }
+
+  Ok(())
+}
+
 pub fn map_pages(start_addr: u64, end_addr: u64, page_flags: PageTableFlags, inclusive: bool) -> Result<(), MapToError<Size4KiB>> {
-  let start_addr = VirtAddr::new(start_addr as u64);
-  let end_addr = VirtAddr::new(end_addr as u64);
+  allocate_and_map(start_addr, end_addr, page_flags, inclusive, tlb::flush_range)
+}
 
-  let start_page = Page::containing_address(start_addr);
-  let end_page = Page::containing_address(end_addr);
+// Flushes only this cpu, a shootdown under the heap lock could wait on a cpu spinning on it.
+pub fn map_new_pages(start_addr: u64, end_addr: u64, page_flags: PageTableFlags, inclusive: bool) -> Result<(), MapToError<Size4KiB>> {
+  allocate_and_map(start_addr, end_addr, page_flags, inclusive, tlb::flush_local)
+}
+
+fn allocate_and_map(
+  start_addr: u64,
+  end_addr: u64,
+  page_flags: PageTableFlags,
+  inclusive: bool,
+  flush: fn(VirtAddr, VirtAddr),
+) -> Result<(), MapToError<Size4KiB>> {
+  let (start, end) = page_range(start_addr, end_addr, inclusive);
 
   let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
   let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
 
-  for page in Page::range(start_page, if inclusive { end_page + 1 } else { end_page }) {
+  let mut addr = start;
+
+  while addr < end {
+    if huge_page_fits(addr, 0, end, Size2MiB::SIZE) {
+      split_huge_pages(&mut mapper, &mut frame_alloc, addr, Size2MiB::SIZE)?;
+
+      if let Some(frame) = frame_alloc.allocate_contiguous(512, 512, Zone::Normal) {
+        let phys = frame.start_address().as_u64();
+
+        match map_page::<Size2MiB>(&mut mapper, &mut frame_alloc, addr, phys, page_flags) {
+          Ok(()) => {
+            addr += Size2MiB::SIZE;
+            continue;
+          }
+          Err(_) => unsafe { frame_alloc.deallocate_contiguous(frame, 512) },
+        }
+      }
+    }
+
+    split_huge_pages(&mut mapper, &mut frame_alloc, addr, Size4KiB::SIZE)?;
+
     let frame = frame_alloc.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
 
-    unsafe {
-      mapper.map_to(page, frame, page_flags, &mut *frame_alloc)?.flush();
+    if let Err(err) = map_page::<Size4KiB>(&mut mapper, &mut frame_alloc, addr, frame.start_address().as_u64(), page_flags) {
+      unsafe { frame_alloc.deallocate_frame(frame) }
+
+      return Err(err);
     }
 
-    log::trace!(
-      "mapped virtual page {:#x} to physical frame {:#x}",
-      page.start_address().as_u64(),
-      frame.start_address().as_u64()
-    );
+    addr += Size4KiB::SIZE;
   }
 
+  drop(frame_alloc);
+  drop(mapper);
+
+  flush(VirtAddr::new(start), VirtAddr::new(end));
+
   Ok(())
 }
 
-pub fn identity_map_pages(start_addr: u64, end_addr: u64, page_flags: PageTableFlags, inclusive: bool) -> Result<(), MapToError<Size4KiB>> {
-  let start_addr = VirtAddr::new(start_addr as u64);
-  let end_addr = VirtAddr::new(end_addr as u64);
+pub fn map_physical_pages(
+  start_addr: u64,
+  end_addr: u64,
+  phys_addr: u64,
+  page_flags: PageTableFlags,
+  inclusive: bool,
+) -> Result<(), MapToError<Size4KiB>> {
+  let (start, end) = page_range(start_addr, end_addr, inclusive);
+  let phys_start = align_down(phys_addr, Size4KiB::SIZE);
+
+  let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+  let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
 
-  let start_page = Page::containing_address(start_addr);
-  let end_page = Page::containing_address(end_addr);
+  let mut addr = start;
+
+  while addr < end {
+    let phys = phys_start + (addr - start);
+
+    let size = if cpu::has_1gib_pages() && huge_page_fits(addr, phys, end, Size1GiB::SIZE) {
+      Size1GiB::SIZE
+    } else if huge_page_fits(addr, phys, end, Size2MiB::SIZE) {
+      Size2MiB::SIZE
+    } else {
+      Size4KiB::SIZE
+    };
+
+    split_huge_pages(&mut mapper, &mut frame_alloc, addr, size)?;
+
+    match size {
+      Size1GiB::SIZE => map_page::<Size1GiB>(&mut mapper, &mut frame_alloc, addr, phys, page_flags).map_err(into_4kib_error)?,
+      Size2MiB::SIZE => map_page::<Size2MiB>(&mut mapper, &mut frame_alloc, addr, phys, page_flags).map_err(into_4kib_error)?,
+      _ => map_page::<Size4KiB>(&mut mapper, &mut frame_alloc, addr, phys, page_flags)?,
+    }
+
+    addr += size;
+  }
+
+  drop(frame_alloc);
+  drop(mapper);
+
+  tlb::flush_range(VirtAddr::new(start), VirtAddr::new(end));
+
+  Ok(())
+}
+
+pub fn map_physical_range(phys: u64, size: u64, page_flags: PageTableFlags) -> Result<VirtAddr, MapToError<Size4KiB>> {
+  let phys_mem_offset = *PHYS_MEM_OFFSET.get().expect("physical memory offset is unknown");
+  let virt = phys_mem_offset + phys;
+  let (start, end) = page_range(virt.as_u64(), virt.as_u64() + size.max(1), false);
 
   let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
   let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
 
-  for page in Page::range(start_page, if inclusive { end_page + 1 } else { end_page }) {
-    let frame = PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64()));
+  for addr in (start..end).step_by(Size4KiB::SIZE as usize) {
+    if mapper.translate_addr(VirtAddr::new(addr)).is_some() {
+      continue;
+    }
+
+    match map_page::<Size4KiB>(&mut mapper, &mut frame_alloc, addr, addr - phys_mem_offset.as_u64(), page_flags) {
+      Ok(()) | Err(MapToError::PageAlreadyMapped(_)) => {}
+      Err(err) => return Err(err),
+    }
+  }
+
+  drop(frame_alloc);
+  drop(mapper);
+
+  // As with `map_new_pages`, no cpu can have cached a translation for the pages that were not mapped.
+  tlb::flush_local(VirtAddr::new(start), VirtAddr::new(end));
+
+  Ok(virt)
+}
+
+fn mapped_size(mapper: &OffsetPageTable<'static>, addr: u64) -> u64 {
+  match mapper.translate(VirtAddr::new(addr)) {
+    TranslateResult::Mapped { frame, .. } => frame.size(),
+    _ => Size4KiB::SIZE,
+  }
+}
+
+fn prepare_huge_page(
+  mapper: &mut OffsetPageTable<'static>,
+  frame_alloc: &mut GlobalFrameAllocator,
+  addr: u64,
+  start: u64,
+  end: u64,
+) -> Result<u64, MapToError<Size4KiB>> {
+  loop {
+    let size = mapped_size(mapper, addr);
+    let base = align_down(addr, size);
+
+    if size == Size4KiB::SIZE || (base >= start && base + size <= end) {
+      return Ok(size);
+    }
+
+    let phys_mem_offset = mapper.phys_offset();
+
+    page_table::split_huge_page(mapper.level_4_table(), phys_mem_offset, VirtAddr::new(addr), frame_alloc)?;
+  }
+}
+
+// The frame is only added to `deferred`, other cpus may still reach it until the shootdown is done.
+fn unmap_page<S: PageSize>(
+  mapper: &mut OffsetPageTable<'static>,
+  addr: u64,
+  deferred: &mut DeferredFrames,
+  release: bool,
+) -> Result<(), UnmapError>
+where
+  OffsetPageTable<'static>: Mapper<S>,
+{
+  let (frame, flush) = mapper.unmap(Page::<S>::containing_address(VirtAddr::new(addr)))?;
+
+  flush.ignore();
+
+  if release {
+    deferred.push(PhysFrame::containing_address(frame.start_address()), S::SIZE / Size4KiB::SIZE);
+  }
+
+  log::trace!("unmapped virtual page {:#x} ({:#x} bytes)", addr, S::SIZE);
+
+  Ok(())
+}
+
+// Frames of a batch are freed only after its shootdown.
+pub fn unmap_pages(start_addr: u64, end_addr: u64, inclusive: bool) -> Result<(), UnmapError> {
+  unmap_range(start_addr, end_addr, inclusive, tlb::flush_range, true)
+}
+
+pub fn unmap_new_pages(start_addr: u64, end_addr: u64, inclusive: bool) -> Result<(), UnmapError> {
+  unmap_range(start_addr, end_addr, inclusive, tlb::flush_local, true)
+}
+
+pub fn unmap_physical_pages(start_addr: u64, end_addr: u64, inclusive: bool) -> Result<(), UnmapError> {
+  unmap_range(start_addr, end_addr, inclusive, tlb::flush_range, false)
+}
+
+fn unmap_range(start_addr: u64, end_addr: u64, inclusive: bool, flush: fn(VirtAddr, VirtAddr), release: bool) -> Result<(), UnmapError> {
+  let (start, end) = page_range(start_addr, end_addr, inclusive);
+
+  let mut deferred = DeferredFrames::new();
+  let mut result = Ok(());
+  let mut addr = start;
+
+  while result.is_ok() && addr < end {
+    let batch_start = addr;
+
+    let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+    let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+    while addr < end && deferred.len() < DEFERRED_RUNS / 2 {
+      let size = match prepare_huge_page(&mut mapper, &mut frame_alloc, addr, start, end) {
+        Ok(size) => size,
+        Err(_) => {
+          result = Err(UnmapError::ParentEntryHugePage);
+          break;
+        }
+      };
 
-    unsafe {
-      mapper.map_to(page, frame, page_flags, &mut *frame_alloc)?.flush();
+      let unmapped = match size {
+        Size1GiB::SIZE => unmap_page::<Size1GiB>(&mut mapper, addr, &mut deferred, release),
+        Size2MiB::SIZE => unmap_page::<Size2MiB>(&mut mapper, addr, &mut deferred, release),
+        _ => unmap_page::<Size4KiB>(&mut mapper, addr, &mut deferred, release),
+      };
+
+      match unmapped {
+        Ok(()) | Err(UnmapError::PageNotMapped) => addr = align_down(addr, size) + size,
+        Err(err) => {
+          result = Err(err);
+          break;
+        }
+      }
     }
 
-    log::trace!(
-      "mapped virtual page {:#x} to physical frame {:#x}",
-      page.start_address().as_u64(),
-      frame.start_address().as_u64()
+    let batch_end = addr.max(batch_start + Size4KiB::SIZE);
+    let phys_mem_offset = mapper.phys_offset();
+
+    page_table::free_empty_tables(
+      mapper.level_4_table(),
+      phys_mem_offset,
+      VirtAddr::new(batch_start),
+      VirtAddr::new(batch_end),
+      &frame_alloc,
+      &mut deferred,
     );
+
+    drop(frame_alloc);
+    drop(mapper);
+
+    flush(VirtAddr::new(batch_start), VirtAddr::new(batch_end));
+
+    unsafe { deferred.release(&mut FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock()) }
   }
 
+  result
+}
+
+fn protect_page<S: PageSize>(
+  mapper: &mut OffsetPageTable<'static>,
+  addr: u64,
+  page_flags: PageTableFlags,
+  mask: PageTableFlags,
+) -> Result<(), FlagUpdateError>
+where
+  OffsetPageTable<'static>: Mapper<S>,
+{
+  let page = Page::<S>::containing_address(VirtAddr::new(addr));
+
+  let flags = match mapper.translate(page.start_address()) {
+    TranslateResult::Mapped { flags, .. } => flags,
+    _ => return Err(FlagUpdateError::PageNotMapped),
+  };
+
+  unsafe { mapper.update_flags(page, (flags - mask) | (page_flags & mask))? }.ignore();
+
   Ok(())
 }
 
+// Only the bits in `mask` are taken from `page_flags`, every other bit of each entry stays as it is.
+pub fn protect_pages(
+  start_addr: u64,
+  end_addr: u64,
+  page_flags: PageTableFlags,
+  mask: PageTableFlags,
+  inclusive: bool,
+) -> Result<(), FlagUpdateError> {
+  let (start, end) = page_range(start_addr, end_addr, inclusive);
+
+  let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+  let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+
+  let mut result = Ok(());
+  let mut addr = start;
+
+  while addr < end {
+    let size = match prepare_huge_page(&mut mapper, &mut frame_alloc, addr, start, end) {
+      Ok(size) => size,
+      Err(_) => {
+        result = Err(FlagUpdateError::ParentEntryHugePage);
+        break;
+      }
+    };
+
+    let updated = match size {
+      Size1GiB::SIZE => protect_page::<Size1GiB>(&mut mapper, addr, page_flags, mask),
+      Size2MiB::SIZE => protect_page::<Size2MiB>(&mut mapper, addr, page_flags, mask),
+      _ => protect_page::<Size4KiB>(&mut mapper, addr, page_flags, mask),
+    };
+
+    if let Err(err) = updated {
+      result = Err(err);
+      break;
+    }
+
+    addr = align_down(addr, size) + size;
+  }
+
+  drop(frame_alloc);
+  drop(mapper);
+
+  // Pages before a failure were already changed, those have to be flushed either way.
+  tlb::flush_range(VirtAddr::new(start), VirtAddr::new(end));
+
+  result
+}
+
 pub fn init(phys_mem_offset: u64, mem_regions: &'static MemoryRegions) {
   let phys_mem_offset = VirtAddr::new(phys_mem_offset);
 
+  MEMORY_REGIONS.call_once(|| mem_regions);
+  memmap::init();
+
+  protection::enable();
+
   unsafe {
-    FRAME_ALLOC.call_once(|| Locked::new(GlobalFrameAllocator::new(mem_regions)));
+    FRAME_ALLOC.call_once(|| IrqTicketLocked::new(GlobalFrameAllocator::new(mem_regions, phys_mem_offset)));
     MAPPER.call_once(|| {
       let l4_table = active_l4_table(phys_mem_offset);
 
-      Locked::new(OffsetPageTable::new(l4_table, phys_mem_offset))
+      IrqLocked::new(OffsetPageTable::new(l4_table, phys_mem_offset))
     });
   }
 
+  mmio::init_pat();
+  vmm::init();
+  address_space::init();
+
   heap::init();
+
+  protection::init();
+  meminfo::log();
 }
@@ -0,0 +1,303 @@
+use super::frame_allocator::{DeferredFrames, GlobalFrameAllocator};
+
+use x86_64::{
+  align_down,
+  structures::paging::{
+    mapper::MapToError, FrameAllocator, FrameDeallocator, Page, PageTable, PageTableEntry, PageTableFlags, PageTableIndex,
+    PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+  },
+  VirtAddr,
+};
+
+pub const L1_COVERAGE: u64 = 512 * 4096;
+pub const L2_COVERAGE: u64 = 512 * L1_COVERAGE;
+
+// Parent tables are always created writable, so that copy-on-write pages can be made writable by their entry alone.
+pub const TABLE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+  PageTableFlags::PRESENT.bits() | PageTableFlags::WRITABLE.bits() | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+pub fn table(phys_mem_offset: VirtAddr, frame: PhysFrame) -> &'static mut PageTable {
+  unsafe { &mut *(phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr() }
+}
+
+fn next_table(phys_mem_offset: VirtAddr, parent: &mut PageTable, index: PageTableIndex) -> Option<&'static mut PageTable> {
+  let entry = &parent[index];
+
+  if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+    None
+  } else {
+    Some(table(phys_mem_offset, entry.frame().ok()?))
+  }
+}
+
+fn is_huge(entry: &PageTableEntry) -> bool {
+  !entry.is_unused() && entry.flags().contains(PageTableFlags::HUGE_PAGE)
+}
+
+fn is_empty(table: &PageTable) -> bool {
+  table.iter().all(|entry| entry.is_unused())
+}
+
+fn free_if_empty(
+  phys_mem_offset: VirtAddr,
+  parent: &mut PageTable,
+  index: PageTableIndex,
+  frame_alloc: &GlobalFrameAllocator,
+  deferred: &mut DeferredFrames,
+) {
+  let empty = match next_table(phys_mem_offset, parent, index) {
+    Some(child) => is_empty(child),
+    None => false,
+  };
+
+  if empty {
+    let frame = parent[index].frame().expect("page table entry does not point to a frame");
+
+    // Bootloader tables are never freed, tables without room are picked up by a later unmap.
+    if frame_alloc.is_allocated(frame) && deferred.try_push(frame, 1) {
+      parent[index].set_unused();
+    }
+  }
+}
+
+pub fn free_empty_tables(
+  l4: &mut PageTable,
+  phys_mem_offset: VirtAddr,
+  start: VirtAddr,
+  end: VirtAddr,
+  frame_alloc: &GlobalFrameAllocator,
+  deferred: &mut DeferredFrames,
+) {
+  let mut addr = align_down(start.as_u64(), L1_COVERAGE);
+
+  while addr < end.as_u64() {
+    let virt = VirtAddr::new(addr);
+
+    if let Some(l3) = next_table(phys_mem_offset, l4, virt.p4_index()) {
+      if let Some(l2) = next_table(phys_mem_offset, l3, virt.p3_index()) {
+        free_if_empty(phys_mem_offset, l2, virt.p2_index(), frame_alloc, deferred);
+      }
+    }
+
+    addr += L1_COVERAGE;
+  }
+
+  let mut addr = align_down(start.as_u64(), L2_COVERAGE);
+
+  while addr < end.as_u64() {
+    let virt = VirtAddr::new(addr);
+
+    if let Some(l3) = next_table(phys_mem_offset, l4, virt.p4_index()) {
+      free_if_empty(phys_mem_offset, l3, virt.p3_index(), frame_alloc, deferred);
+    }
+
+    addr += L2_COVERAGE;
+  }
+}
+
+fn split_entry(
+  entry: &mut PageTableEntry,
+  phys_mem_offset: VirtAddr,
+  child_size: u64,
+  frame_alloc: &mut GlobalFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+  let frame = frame_alloc.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+  let flags = entry.flags();
+  let base = entry.addr();
+
+  let child_flags = if child_size == 4096 {
+    flags - PageTableFlags::HUGE_PAGE
+  } else {
+    flags
+  };
+
+  let child_table = table(phys_mem_offset, frame);
+
+  for (index, child) in child_table.iter_mut().enumerate() {
+    child.set_addr(base + index as u64 * child_size, child_flags);
+  }
+
+  entry.set_addr(frame.start_address(), flags & TABLE_FLAGS);
+
+  Ok(())
+}
+
+pub fn split_huge_page(
+  l4: &mut PageTable,
+  phys_mem_offset: VirtAddr,
+  addr: VirtAddr,
+  frame_alloc: &mut GlobalFrameAllocator,
+) -> Result<bool, MapToError<Size4KiB>> {
+  let l3 = match next_table(phys_mem_offset, l4, addr.p4_index()) {
+    Some(l3) => l3,
+    None => return Ok(false),
+  };
+
+  let l3_entry = &mut l3[addr.p3_index()];
+
+  if l3_entry.is_unused() {
+    return Ok(false);
+  }
+
+  if l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+    split_entry(l3_entry, phys_mem_offset, L1_COVERAGE, frame_alloc)?;
+
+    return Ok(true);
+  }
+
+  let l2 = table(phys_mem_offset, l3_entry.frame().map_err(|_| MapToError::ParentEntryHugePage)?);
+  let l2_entry = &mut l2[addr.p2_index()];
+
+  if !l2_entry.is_unused() && l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+    split_entry(l2_entry, phys_mem_offset, 4096, frame_alloc)?;
+
+    return Ok(true);
+  }
+
+  Ok(false)
+}
+
+pub fn count_tables(l4: &mut PageTable, phys_mem_offset: VirtAddr) -> usize {
+  fn count(table: &mut PageTable, phys_mem_offset: VirtAddr, level: usize) -> usize {
+    if level == 1 {
+      return 1;
+    }
+
+    let mut tables = 1;
+
+    for index in 0..512 {
+      if let Some(child) = next_table(phys_mem_offset, table, PageTableIndex::new(index)) {
+        tables += count(child, phys_mem_offset, level - 1);
+      }
+    }
+
+    tables
+  }
+
+  count(l4, phys_mem_offset, 4)
+}
+
+// Writable and user accessible only hold if every level allows them, no-execute holds if any level sets it.
+fn effective_flags(parent: PageTableFlags, flags: PageTableFlags) -> PageTableFlags {
+  let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+  (flags - inherited) | (flags & parent & inherited) | (parent & PageTableFlags::NO_EXECUTE)
+}
+
+pub fn for_each_page(l4: &mut PageTable, phys_mem_offset: VirtAddr, mut f: impl FnMut(VirtAddr, u64, PageTableFlags)) {
+  fn walk(
+    entries: &PageTable,
+    phys_mem_offset: VirtAddr,
+    level: usize,
+    base: u64,
+    parent_flags: PageTableFlags,
+    f: &mut dyn FnMut(VirtAddr, u64, PageTableFlags),
+  ) {
+    let coverage = 4096_u64 << (9 * (level - 1));
+
+    for (index, entry) in entries.iter().enumerate() {
+      let flags = entry.flags();
+
+      if !flags.contains(PageTableFlags::PRESENT) {
+        continue;
+      }
+
+      let addr = base + index as u64 * coverage;
+      let flags = effective_flags(parent_flags, flags);
+
+      if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+        f(VirtAddr::new_truncate(addr), coverage, flags);
+      } else if let Ok(frame) = entry.frame() {
+        walk(table(phys_mem_offset, frame), phys_mem_offset, level - 1, addr, flags, f);
+      }
+    }
+  }
+
+  let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+  walk(l4, phys_mem_offset, 4, 0, flags, &mut f);
+}
+
+pub fn free_tree(phys_mem_offset: VirtAddr, frame: PhysFrame, level: usize, frame_alloc: &mut GlobalFrameAllocator) {
+  for entry in table(phys_mem_offset, frame).iter_mut() {
+    let flags = entry.flags();
+
+    if !flags.contains(PageTableFlags::PRESENT) {
+      continue;
+    }
+
+    if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+      let start = PhysFrame::containing_address(entry.addr());
+      let pages = (4096_u64 << (9 * (level - 1))) / 4096;
+
+      for page_frame in PhysFrame::range(start, start + pages) {
+        if frame_alloc.is_allocated(page_frame) {
+          unsafe { frame_alloc.deallocate_frame(page_frame) }
+        }
+      }
+    } else if let Ok(child) = entry.frame() {
+      free_tree(phys_mem_offset, child, level - 1, frame_alloc);
+    }
+
+    entry.set_unused();
+  }
+
+  if frame_alloc.is_allocated(frame) {
+    unsafe { frame_alloc.deallocate_frame(frame) }
+  }
+}
+
+pub fn is_user_entry(entry: &PageTableEntry, kernel_entry: &PageTableEntry) -> bool {
+  !entry.is_unused() && (kernel_entry.is_unused() || kernel_entry.addr() != entry.addr())
+}
+
+pub fn for_each_user_page(
+  l4: &mut PageTable,
+  kernel_l4: &PageTable,
+  phys_mem_offset: VirtAddr,
+  mut f: impl FnMut(Page, &mut PageTableEntry),
+) -> Result<(), VirtAddr> {
+  for l4_index in (0..256).map(PageTableIndex::new) {
+    if !is_user_entry(&l4[l4_index], &kernel_l4[l4_index]) {
+      continue;
+    }
+
+    let l3 = match next_table(phys_mem_offset, l4, l4_index) {
+      Some(l3) => l3,
+      None => continue,
+    };
+
+    for l3_index in (0..512).map(PageTableIndex::new) {
+      if is_huge(&l3[l3_index]) {
+        return Err(Page::<Size1GiB>::from_page_table_indices_1gib(l4_index, l3_index).start_address());
+      }
+
+      let l2 = match next_table(phys_mem_offset, l3, l3_index) {
+        Some(l2) => l2,
+        None => continue,
+      };
+
+      for l2_index in (0..512).map(PageTableIndex::new) {
+        if is_huge(&l2[l2_index]) {
+          return Err(Page::<Size2MiB>::from_page_table_indices_2mib(l4_index, l3_index, l2_index).start_address());
+        }
+
+        let l1 = match next_table(phys_mem_offset, l2, l2_index) {
+          Some(l1) => l1,
+          None => continue,
+        };
+
+        for (l1_index, entry) in l1.iter_mut().enumerate() {
+          if entry.flags().contains(PageTableFlags::PRESENT) {
+            let page = Page::from_page_table_indices(l4_index, l3_index, l2_index, PageTableIndex::new(l1_index as u16));
+
+            f(page, entry);
+          }
+        }
+      }
+    }
+  }
+
+  Ok(())
+}
@@ -0,0 +1,171 @@
+use super::{page_table, protect_pages, tlb, MAPPER, MEMORY_REGIONS};
+use crate::utils::symbols;
+
+use x86_64::{
+  align_down, align_up,
+  registers::{
+    control::{Cr0, Cr0Flags},
+    model_specific::{Efer, EferFlags},
+  },
+  structures::paging::{PageSize, PageTableFlags, Size4KiB},
+};
+use xmas_elf::program::Type;
+
+// NO_EXECUTE is reserved until NXE is set.
+pub fn enable() {
+  unsafe {
+    Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+    Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+  }
+
+  log::info!("enabled no-execute and write protection");
+}
+
+fn segment_flags(write: bool, execute: bool) -> PageTableFlags {
+  let mut flags = PageTableFlags::empty();
+
+  if write {
+    flags |= PageTableFlags::WRITABLE;
+  }
+
+  if !execute {
+    flags |= PageTableFlags::NO_EXECUTE;
+  }
+
+  flags
+}
+
+fn protect_segment(start: u64, end: u64, write: bool, execute: bool) {
+  if start >= end {
+    return;
+  }
+
+  // Segments have to be laid out so that no page needs both, there is no safe way to map it.
+  if write && execute {
+    panic!("kernel pages {:#x}..{:#x} would be writable and executable", start, end);
+  }
+
+  let mask = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+  if let Err(err) = protect_pages(start, end, segment_flags(write, execute), mask, false) {
+    log::error!("failed to protect kernel pages {:#x}..{:#x}: {:?}", start, end, err);
+  }
+}
+
+pub fn protect_kernel() {
+  let kernel_file = symbols::kernel_file();
+  let mut previous: Option<(u64, bool, bool)> = None;
+
+  for header in kernel_file.program_iter() {
+    if header.get_type() != Ok(Type::Load) || header.mem_size() == 0 {
+      continue;
+    }
+
+    let flags = header.flags();
+    let (write, execute) = (flags.is_write(), flags.is_execute());
+
+    let mut start = align_down(header.virtual_addr(), Size4KiB::SIZE);
+    let end = align_up(header.virtual_addr() + header.mem_size(), Size4KiB::SIZE);
+
+    if let Some((previous_end, previous_write, previous_execute)) = previous {
+      if previous_end > start {
+        protect_segment(start, start + Size4KiB::SIZE, write || previous_write, execute || previous_execute);
+        start += Size4KiB::SIZE;
+      }
+    }
+
+    protect_segment(start, end, write, execute);
+
+    log::debug!(
+      "kernel segment {:#x}..{:#x} is r{}{}",
+      header.virtual_addr(),
+      header.virtual_addr() + header.mem_size(),
+      if write { "w" } else { "-" },
+      if execute { "x" } else { "-" }
+    );
+
+    previous = Some((end, write, execute));
+  }
+}
+
+// The physical memory mapping gets no-execute on its level 4 entries, which covers every page below them.
+pub fn protect_physical_memory() {
+  let phys_end = MEMORY_REGIONS
+    .get()
+    .expect("memory regions are unknown")
+    .iter()
+    .map(|region| region.end)
+    .max()
+    .unwrap_or(0);
+
+  let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+  let phys_mem_offset = mapper.phys_offset();
+
+  let start = phys_mem_offset;
+  let end = phys_mem_offset + phys_end.max(1);
+  let l4 = mapper.level_4_table();
+
+  for index in u16::from(start.p4_index())..=u16::from((end - 1_u64).p4_index()) {
+    let entry = &mut l4[index as usize];
+
+    if !entry.is_unused() {
+      entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+    }
+  }
+
+  drop(mapper);
+
+  tlb::flush_range(start, end);
+}
+
+fn report(start: u64, end: u64) {
+  log::warn!("pages {:#x}..{:#x} are writable and executable", start, end);
+}
+
+pub fn audit() -> u64 {
+  let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+  let phys_mem_offset = mapper.phys_offset();
+
+  let mut total = 0;
+  let mut current: Option<(u64, u64)> = None;
+
+  page_table::for_each_page(mapper.level_4_table(), phys_mem_offset, |addr, size, flags| {
+    let addr = addr.as_u64();
+
+    if !flags.contains(PageTableFlags::WRITABLE) || flags.contains(PageTableFlags::NO_EXECUTE) {
+      return;
+    }
+
+    total += size;
+
+    current = match current {
+      Some((start, end)) if end == addr => Some((start, addr + size)),
+      Some((start, end)) => {
+        report(start, end);
+        Some((addr, addr + size))
+      }
+      None => Some((addr, addr + size)),
+    };
+  });
+
+  if let Some((start, end)) = current {
+    report(start, end);
+  }
+
+  drop(mapper);
+
+  if total == 0 {
+    log::info!("no writable and executable pages are mapped");
+  } else {
+    log::warn!("{} KiB of writable and executable pages are mapped", total / 1024);
+  }
+
+  total
+}
+
+pub fn init() {
+  protect_kernel();
+  protect_physical_memory();
+
+  audit();
+}
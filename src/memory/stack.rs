@@ -0,0 +1,160 @@
+use super::{
+  map_pages, unmap_pages,
+  vmm::{self, Region},
+};
+
+use core::{
+  mem,
+  sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+  align_up,
+  structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
+  VirtAddr,
+};
+
+pub const DEFAULT_STACK_SIZE: u64 = 64 * 1024;
+
+const GUARD_SIZE: u64 = 4096;
+const MAX_STACKS: usize = 256;
+
+#[derive(Debug)]
+pub enum StackError {
+  NoAddressSpace,
+  Map(MapToError<Size4KiB>),
+}
+
+// Bounds of every live kernel stack, kept lock free so the unwinder can use them from any context, panics included.
+struct StackBounds {
+  bottom: AtomicU64,
+  top: AtomicU64,
+}
+
+const NO_STACK: StackBounds = StackBounds {
+  bottom: AtomicU64::new(0),
+  top: AtomicU64::new(0),
+};
+
+static STACKS: [StackBounds; MAX_STACKS] = [NO_STACK; MAX_STACKS];
+
+fn register(bottom: u64, top: u64) {
+  for stack in STACKS.iter() {
+    if stack
+      .bottom
+      .compare_exchange(0, bottom, Ordering::AcqRel, Ordering::Relaxed)
+      .is_ok()
+    {
+      stack.top.store(top, Ordering::Release);
+
+      return;
+    }
+  }
+
+  log::warn!(
+    "kernel stack {:#x}..{:#x} has no bounds slot left, backtraces on it are unchecked",
+    bottom,
+    top
+  );
+}
+
+fn unregister(bottom: u64) {
+  for stack in STACKS.iter() {
+    if stack.bottom.load(Ordering::Acquire) == bottom {
+      stack.top.store(0, Ordering::Release);
+      stack.bottom.store(0, Ordering::Release);
+
+      return;
+    }
+  }
+}
+
+pub fn bounds(addr: VirtAddr) -> Option<(VirtAddr, VirtAddr)> {
+  let addr = addr.as_u64();
+
+  STACKS.iter().find_map(|stack| {
+    let bottom = stack.bottom.load(Ordering::Acquire);
+    let top = stack.top.load(Ordering::Acquire);
+
+    if bottom != 0 && (bottom..top).contains(&addr) {
+      Some((VirtAddr::new(bottom), VirtAddr::new(top)))
+    } else {
+      None
+    }
+  })
+}
+
+pub struct KernelStack {
+  base: VirtAddr,
+  bottom: VirtAddr,
+  top: VirtAddr,
+}
+
+impl KernelStack {
+  pub fn new(size: u64) -> Result<Self, StackError> {
+    let size = align_up(size.max(1), 4096);
+
+    let base = vmm::allocate(Region::Stacks, size + GUARD_SIZE).ok_or(StackError::NoAddressSpace)?;
+    let bottom = base + GUARD_SIZE;
+    let top = bottom + size;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    if let Err(err) = map_pages(bottom.as_u64(), top.as_u64(), flags, false) {
+      unmap_pages(bottom.as_u64(), top.as_u64(), false).expect("failed to unmap kernel stack");
+      vmm::free(Region::Stacks, base);
+
+      return Err(StackError::Map(err));
+    }
+
+    register(bottom.as_u64(), top.as_u64());
+
+    log::debug!("allocated kernel stack {:#x}..{:#x}", bottom.as_u64(), top.as_u64());
+
+    Ok(Self { base, bottom, top })
+  }
+
+  pub fn bottom(&self) -> VirtAddr {
+    self.bottom
+  }
+
+  pub fn top(&self) -> VirtAddr {
+    self.top
+  }
+
+  pub fn size(&self) -> u64 {
+    self.top - self.bottom
+  }
+
+  // Keeps the stack mapped forever, for stacks the cpu keeps using on its own like the ones in the tss.
+  pub fn leak(self) -> VirtAddr {
+    let top = self.top;
+
+    mem::forget(self);
+
+    top
+  }
+
+  pub unsafe fn switch_to(self, entry: extern "C" fn(usize) -> !, arg: usize) -> ! {
+    let top = self.leak();
+
+    asm!(
+      "mov rsp, {top}",
+      "xor ebp, ebp",
+      "call {entry}",
+      "ud2",
+      top = in(reg) top.as_u64(),
+      entry = in(reg) entry,
+      in("rdi") arg,
+      options(noreturn)
+    );
+  }
+}
+
+impl Drop for KernelStack {
+  fn drop(&mut self) {
+    unregister(self.bottom.as_u64());
+
+    unmap_pages(self.bottom.as_u64(), self.top.as_u64(), false).expect("failed to unmap kernel stack");
+    vmm::free(Region::Stacks, self.base);
+  }
+}
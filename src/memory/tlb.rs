@@ -0,0 +1,136 @@
+use super::address_space;
+use crate::{
+  cpu::percpu,
+  interrupts::{apic, TLB_SHOOTDOWN_VECTOR},
+};
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::{instructions::tlb, structures::paging::{Page, Size4KiB}, VirtAddr};
+
+// Above this many pages a full flush is cheaper than invalidating page by page.
+const MAX_PAGE_FLUSHES: u64 = 32;
+
+const INVPCID_ALL_CONTEXTS: u64 = 2;
+
+static SHOOTDOWN_ACTIVE: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_USER: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_END: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+fn flush_all_contexts() {
+  let descriptor = [0_u64; 2];
+
+  unsafe {
+    asm!(
+      "invpcid {}, [{}]",
+      in(reg) INVPCID_ALL_CONTEXTS,
+      in(reg) &descriptor,
+      options(nostack, preserves_flags)
+    );
+  }
+}
+
+fn flush_local_pages(start: VirtAddr, end: VirtAddr) {
+  let start_page = Page::<Size4KiB>::containing_address(start);
+  let end_page = Page::containing_address(end - 1_u64);
+  let pages = (end_page.start_address() - start_page.start_address()) / 4096 + 1;
+
+  if pages > MAX_PAGE_FLUSHES {
+    tlb::flush_all();
+  } else {
+    for page in Page::range_inclusive(start_page, end_page) {
+      tlb::flush(page.start_address());
+    }
+  }
+}
+
+pub fn flush_local(start: VirtAddr, end: VirtAddr) {
+  if address_space::pcid_enabled() {
+    flush_all_contexts();
+  } else {
+    flush_local_pages(start, end);
+  }
+}
+
+pub fn flush_user_local(start: VirtAddr, end: VirtAddr) {
+  flush_local_pages(start, end);
+}
+
+fn flush(start: VirtAddr, end: VirtAddr, user: bool) {
+  if user {
+    flush_local_pages(start, end);
+  } else {
+    flush_local(start, end);
+  }
+}
+
+// Never called with a spinlock held, a cpu spinning on it would never acknowledge.
+pub fn flush_range(start: VirtAddr, end: VirtAddr) {
+  shootdown(start, end, false);
+}
+
+// Cpus on another address space flush its pcid when they switch to it.
+pub fn flush_user_range(start: VirtAddr, end: VirtAddr) {
+  shootdown(start, end, true);
+}
+
+fn shootdown(start: VirtAddr, end: VirtAddr, user: bool) {
+  if start >= end {
+    return;
+  }
+
+  flush(start, end, user);
+
+  let remote_cpus = percpu::online_count().saturating_sub(1);
+
+  if remote_cpus == 0 || !apic::is_initialized() {
+    return;
+  }
+
+  while SHOOTDOWN_ACTIVE
+    .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+    .is_err()
+  {
+    // Another cpu may be waiting on us while we wait on it with interrupts disabled.
+    service_pending();
+    core::hint::spin_loop();
+  }
+
+  SHOOTDOWN_USER.store(user, Ordering::Relaxed);
+  SHOOTDOWN_START.store(start.as_u64(), Ordering::Relaxed);
+  SHOOTDOWN_END.store(end.as_u64(), Ordering::Relaxed);
+  SHOOTDOWN_PENDING.store(0, Ordering::Relaxed);
+
+  let current = percpu::current().index;
+
+  // Counted before flagged, so no acknowledgement comes first.
+  for cpu in percpu::online_cpus().filter(|cpu| cpu.index != current) {
+    SHOOTDOWN_PENDING.fetch_add(1, Ordering::AcqRel);
+    cpu.tlb_shootdown_pending.store(true, Ordering::Release);
+  }
+
+  apic::broadcast(TLB_SHOOTDOWN_VECTOR);
+
+  while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+    core::hint::spin_loop();
+  }
+
+  SHOOTDOWN_ACTIVE.store(false, Ordering::Release);
+}
+
+pub fn service_pending() {
+  let cpu = match percpu::try_current() {
+    Some(cpu) => cpu,
+    None => return,
+  };
+
+  if cpu.tlb_shootdown_pending.swap(false, Ordering::AcqRel) {
+    let start = VirtAddr::new(SHOOTDOWN_START.load(Ordering::Relaxed));
+    let end = VirtAddr::new(SHOOTDOWN_END.load(Ordering::Relaxed));
+
+    flush(start, end, SHOOTDOWN_USER.load(Ordering::Relaxed));
+
+    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+  }
+}
@@ -0,0 +1,206 @@
+use super::{address_space, FRAME_ALLOC, MAPPER};
+use crate::utils::rwlock::IrqRwLocked;
+
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::{
+  align_down, align_up,
+  structures::{
+    idt::PageFaultErrorCode,
+    paging::{mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB},
+  },
+  VirtAddr,
+};
+
+// Marks a page that was writable before its frame got shared, the first write to it copies the frame.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// Faults only read the list, so they can be resolved on several cpus at once.
+static KERNEL_VMAS: IrqRwLocked<VmaList> = IrqRwLocked::new(VmaList::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backing {
+  Anonymous,
+  Guard,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmaError {
+  Empty,
+  Overlap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultError {
+  NoArea,
+  Guard,
+  AccessDenied,
+  OutOfMemory,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Vma {
+  pub start: VirtAddr,
+  pub end: VirtAddr,
+  pub flags: PageTableFlags,
+  pub backing: Backing,
+}
+
+impl Vma {
+  pub fn new(start: VirtAddr, end: VirtAddr, flags: PageTableFlags, backing: Backing) -> Self {
+    Self {
+      start: VirtAddr::new(align_down(start.as_u64(), 4096)),
+      end: VirtAddr::new(align_up(end.as_u64(), 4096)),
+      flags: flags | PageTableFlags::PRESENT,
+      backing,
+    }
+  }
+
+  pub fn contains(&self, addr: VirtAddr) -> bool {
+    (self.start..self.end).contains(&addr)
+  }
+
+  pub fn permits(&self, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !self.flags.contains(PageTableFlags::WRITABLE) {
+      return false;
+    }
+
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && self.flags.contains(PageTableFlags::NO_EXECUTE) {
+      return false;
+    }
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) && !self.flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+      return false;
+    }
+
+    true
+  }
+}
+
+#[derive(Clone)]
+pub struct VmaList {
+  areas: Vec<Vma>,
+}
+
+impl VmaList {
+  pub const fn new() -> Self {
+    Self { areas: Vec::new() }
+  }
+
+  pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+    if vma.start >= vma.end {
+      return Err(VmaError::Empty);
+    }
+
+    let index = self
+      .areas
+      .iter()
+      .position(|area| area.start >= vma.start)
+      .unwrap_or(self.areas.len());
+
+    let overlaps_previous = index > 0 && self.areas[index - 1].end > vma.start;
+    let overlaps_next = self.areas.get(index).map_or(false, |next| next.start < vma.end);
+
+    if overlaps_previous || overlaps_next {
+      return Err(VmaError::Overlap);
+    }
+
+    self.areas.insert(index, vma);
+
+    Ok(())
+  }
+
+  pub fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+    let index = self.areas.iter().position(|area| area.start == start)?;
+
+    Some(self.areas.remove(index))
+  }
+
+  pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+    self.areas.iter().find(|area| area.contains(addr))
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+    self.areas.iter()
+  }
+}
+
+// Maps a zeroed frame at the page containing `addr`. Another cpu faulting on the same page first counts as success.
+pub fn populate(mapper: &mut OffsetPageTable<'static>, addr: VirtAddr, flags: PageTableFlags) -> Result<(), FaultError> {
+  let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+  let frame = frame_alloc.allocate_frame().ok_or(FaultError::OutOfMemory)?;
+
+  unsafe { ptr::write_bytes((mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr::<u8>(), 0, 4096) }
+
+  let page = Page::<Size4KiB>::containing_address(addr);
+
+  match unsafe { mapper.map_to(page, frame, flags, &mut *frame_alloc) } {
+    Ok(flush) => {
+      flush.ignore();
+
+      Ok(())
+    }
+    Err(err) => {
+      unsafe { frame_alloc.deallocate_frame(frame) }
+
+      match err {
+        MapToError::PageAlreadyMapped(_) => Ok(()),
+        MapToError::FrameAllocationFailed => Err(FaultError::OutOfMemory),
+        MapToError::ParentEntryHugePage => Err(FaultError::AccessDenied),
+      }
+    }
+  }
+}
+
+pub fn resolve(vma: Option<&Vma>, error_code: PageFaultErrorCode) -> Result<PageTableFlags, FaultError> {
+  let vma = vma.ok_or(FaultError::NoArea)?;
+
+  if vma.backing == Backing::Guard {
+    return Err(FaultError::Guard);
+  }
+
+  if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) || !vma.permits(error_code) {
+    return Err(FaultError::AccessDenied);
+  }
+
+  Ok(vma.flags)
+}
+
+pub fn insert_kernel(vma: Vma) -> Result<(), VmaError> {
+  KERNEL_VMAS.write().insert(vma)
+}
+
+pub fn remove_kernel(start: VirtAddr) -> Option<Vma> {
+  KERNEL_VMAS.write().remove(start)
+}
+
+// Lazily populated kernel memory must not be touched while holding the mapper lock, this takes it.
+fn handle_kernel_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+  let flags = resolve(KERNEL_VMAS.read().find(addr), error_code)?;
+  let mapper = MAPPER.get().expect("mapper has not been initialized");
+  let frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized");
+
+  if mapper.is_held_by_current_cpu() || frame_alloc.is_held_by_current_cpu() {
+    panic!(
+      "touched lazily populated kernel memory at {:#x} while holding the mapper or frame allocator lock",
+      addr.as_u64()
+    );
+  }
+
+  let mut mapper = mapper.lock();
+
+  populate(&mut mapper, addr, flags)
+}
+
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode, interruptible: bool) -> Result<(), FaultError> {
+  if addr.as_u64() < address_space::USER_END {
+    if let Some(address_space) = address_space::current() {
+      match address_space.handle_fault(addr, error_code, interruptible) {
+        Err(FaultError::NoArea) => {}
+        result => return result,
+      }
+    }
+  }
+
+  handle_kernel_fault(addr, error_code)
+}
@@ -0,0 +1,236 @@
+use super::{
+  map_new_pages, page_table, unmap_new_pages, unmap_pages,
+  vma::{self, Backing, Vma},
+  FRAME_ALLOC, MAPPER,
+};
+use crate::utils::locked::IrqLocked;
+
+use x86_64::{
+  align_up,
+  structures::paging::{FrameAllocator, PageTable, PageTableFlags, Translate},
+  VirtAddr,
+};
+
+const REGION_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+const GUARD_SIZE: u64 = 4096;
+const MAX_ALLOCATIONS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+  Heap = 0,
+  Vmalloc = 1,
+  Mmio = 2,
+  Stacks = 3,
+  PerCpu = 4,
+}
+
+impl Region {
+  pub const ALL: [Region; 5] = [Region::Heap, Region::Vmalloc, Region::Mmio, Region::Stacks, Region::PerCpu];
+
+  pub fn start(self) -> VirtAddr {
+    VirtAddr::new(0xffff_c000_0000_0000 + self as u64 * REGION_SIZE)
+  }
+
+  pub fn end(self) -> VirtAddr {
+    self.start() + REGION_SIZE
+  }
+
+  pub fn contains(self, addr: VirtAddr) -> bool {
+    (self.start()..self.end()).contains(&addr)
+  }
+}
+
+#[derive(Clone, Copy)]
+struct Allocation {
+  start: u64,
+  end: u64,
+}
+
+#[derive(Clone, Copy)]
+struct RegionAllocator {
+  allocations: [Allocation; MAX_ALLOCATIONS],
+  count: usize,
+}
+
+impl RegionAllocator {
+  const fn new() -> Self {
+    Self {
+      allocations: [Allocation { start: 0, end: 0 }; MAX_ALLOCATIONS],
+      count: 0,
+    }
+  }
+
+  fn allocate(&mut self, region: Region, size: u64, align: u64) -> Option<u64> {
+    if self.count == MAX_ALLOCATIONS {
+      return None;
+    }
+
+    let mut candidate = align_up(region.start().as_u64() + GUARD_SIZE, align);
+
+    for index in 0..=self.count {
+      let limit = match self.allocations[..self.count].get(index) {
+        Some(allocation) => allocation.start,
+        None => region.end().as_u64(),
+      };
+
+      // Every later candidate lies even higher, so an overflow here means nothing fits.
+      if candidate.checked_add(size)?.checked_add(GUARD_SIZE)? <= limit {
+        self.allocations.copy_within(index..self.count, index + 1);
+        self.allocations[index] = Allocation {
+          start: candidate,
+          end: candidate + size,
+        };
+        self.count += 1;
+
+        return Some(candidate);
+      }
+
+      if index < self.count {
+        candidate = align_up(self.allocations[index].end + GUARD_SIZE, align);
+      }
+    }
+
+    None
+  }
+
+  fn size(&self, start: u64) -> Option<u64> {
+    let allocation = self.allocations[..self.count].iter().find(|allocation| allocation.start == start)?;
+
+    Some(allocation.end - allocation.start)
+  }
+
+  fn free(&mut self, start: u64) -> Option<u64> {
+    let index = self.allocations[..self.count].iter().position(|allocation| allocation.start == start)?;
+    let allocation = self.allocations[index];
+
+    self.allocations.copy_within(index + 1..self.count, index);
+    self.count -= 1;
+
+    Some(allocation.end - allocation.start)
+  }
+
+  fn used(&self) -> u64 {
+    self.allocations[..self.count].iter().map(|allocation| allocation.end - allocation.start).sum()
+  }
+}
+
+static REGIONS: IrqLocked<[RegionAllocator; 5]> = IrqLocked::new([RegionAllocator::new(); 5]);
+
+pub fn init() {
+  let mut mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+  let mut frame_alloc = FRAME_ALLOC.get().expect("frame allocator has not been initialized").lock();
+  let phys_mem_offset = mapper.phys_offset();
+  let l4 = mapper.level_4_table();
+
+  for &region in Region::ALL.iter() {
+    let entry = &mut l4[region.start().p4_index()];
+
+    if !entry.is_unused() {
+      panic!("{:?} region at {:#x} is already in use", region, region.start().as_u64());
+    }
+
+    let frame = frame_alloc
+      .allocate_frame()
+      .expect("failed to allocate a level 3 table for a region");
+
+    *page_table::table(phys_mem_offset, frame) = PageTable::new();
+    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
+    log::debug!(
+      "{:?} region spans {:#x}..{:#x}",
+      region,
+      region.start().as_u64(),
+      region.end().as_u64()
+    );
+  }
+}
+
+pub fn allocate(region: Region, size: u64) -> Option<VirtAddr> {
+  allocate_aligned(region, size, 4096)
+}
+
+pub fn allocate_aligned(region: Region, size: u64, align: u64) -> Option<VirtAddr> {
+  let size = align_up(size.max(1), 4096);
+  let start = REGIONS.lock()[region as usize].allocate(region, size, align.max(4096))?;
+
+  Some(VirtAddr::new(start))
+}
+
+pub fn free(region: Region, addr: VirtAddr) -> Option<u64> {
+  REGIONS.lock()[region as usize].free(addr.as_u64())
+}
+
+pub fn used(region: Region) -> u64 {
+  REGIONS.lock()[region as usize].used()
+}
+
+pub fn vmalloc(size: u64) -> Option<VirtAddr> {
+  vmalloc_aligned(size, 4096)
+}
+
+// Ranges were shot down when freed, so a local flush is enough.
+pub fn vmalloc_aligned(size: u64, align: u64) -> Option<VirtAddr> {
+  let start = allocate_aligned(Region::Vmalloc, size, align)?;
+  let size = align_up(size.max(1), 4096);
+
+  let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+  if map_new_pages(start.as_u64(), start.as_u64() + size, flags, false).is_err() {
+    unmap_new_pages(start.as_u64(), start.as_u64() + size, false).expect("failed to unmap vmalloc range");
+    free(Region::Vmalloc, start);
+
+    return None;
+  }
+
+  Some(start)
+}
+
+// Never touch the range while holding the mapper lock.
+pub fn vreserve(size: u64) -> Option<VirtAddr> {
+  let start = allocate(Region::Vmalloc, size)?;
+  let size = align_up(size.max(1), 4096);
+
+  let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+  if vma::insert_kernel(Vma::new(start, start + size, flags, Backing::Anonymous)).is_err() {
+    free(Region::Vmalloc, start);
+
+    return None;
+  }
+
+  Some(start)
+}
+
+pub fn vfree(addr: VirtAddr) {
+  let size = REGIONS.lock()[Region::Vmalloc as usize]
+    .size(addr.as_u64())
+    .expect("tried to vfree an address that was not vmalloc'ed");
+
+  vma::remove_kernel(addr);
+
+  unmap_pages(addr.as_u64(), addr.as_u64() + size, false).expect("failed to unmap vmalloc range");
+
+  free(Region::Vmalloc, addr);
+}
+
+pub fn check() {
+  let mapped = vmalloc(4096).expect("failed to vmalloc a page");
+  let reserved = vreserve(2 * 4096).expect("failed to reserve a vmalloc range");
+
+  unsafe {
+    mapped.as_mut_ptr::<u64>().write_volatile(1);
+    reserved.as_mut_ptr::<u64>().write_volatile(1);
+  }
+
+  let mapper = MAPPER.get().expect("mapper has not been initialized").lock();
+  let populated = mapper.translate_addr(reserved).is_some() && mapper.translate_addr(reserved + 4096_u64).is_none();
+
+  drop(mapper);
+
+  assert!(populated, "reserved kernel memory was not populated on first access");
+
+  vfree(reserved);
+  vfree(mapped);
+
+  log::info!("populated reserved kernel memory at {:#x} on first access", reserved.as_u64());
+}
@@ -1,23 +1,12 @@
-use crate::{KERNEL_INFO, PHYS_MEM_OFFSET};
+use crate::utils::symbols::{self, ReturnAddresses};
 
 use core::{
   panic::PanicInfo,
   sync::atomic::{AtomicBool, Ordering},
 };
-use xmas_elf::{
-  sections::{SectionData, ShType},
-  symbol_table::Entry,
-  ElfFile,
-};
 
 static BACKTRACE: AtomicBool = AtomicBool::new(true);
 
-#[repr(C)]
-struct StackFrame {
-  previous: *const StackFrame,
-  return_addr: usize,
-}
-
 struct BacktraceGuard {
   previous: bool,
 }
@@ -46,6 +35,10 @@ impl Drop for BacktraceGuard {
 extern "C" fn rust_begin_unwind(info: &PanicInfo) -> ! {
   let backtrace = BacktraceGuard::new();
 
+  unsafe {
+    crate::early_boot::force_unlock_logger();
+  }
+
   match (info.location(), info.message()) {
     (Some(loc), Some(message)) => log::error!("kernel panicked at {}:{}: {}", loc.file(), loc.line(), message),
     (Some(loc), None) => log::error!("kernel panicked at {}:{}", loc.file(), loc.line()),
@@ -54,56 +47,17 @@ extern "C" fn rust_begin_unwind(info: &PanicInfo) -> ! {
   }
 
   if backtrace.enabled() {
-    let kernel_info = KERNEL_INFO.get().expect("no kernel info was located");
-    let phys_memory_offset = PHYS_MEM_OFFSET.get().expect("how did we get here?").as_u64();
-
-    let kernel_data = unsafe {
-      core::slice::from_raw_parts(
-        (kernel_info.kernel_base + phys_memory_offset) as *const u8,
-        kernel_info.kernel_size as usize,
-      )
-    };
-
-    let kernel_file = ElfFile::new(kernel_data).expect("could not read kernel binary");
-    let symbols_data = kernel_file
-      .section_iter()
-      .find(|sect| sect.get_type() == Ok(ShType::SymTab))
-      .map(|sect| sect.get_data(&kernel_file))
-      .unwrap();
-
-    if let SectionData::SymbolTable64(symbol_table) = symbols_data.unwrap() {
-      let mut stack_frame: *const StackFrame;
-
-      unsafe { asm!("mov {}, rbp", out(reg) stack_frame) }
-
-      if stack_frame.is_null() {
-        log::error!("frame pointers were not emitted for this build, cannot print backtrace");
-      } else {
-        for _ in 0..64 {
-          let stack_frame_ref = unsafe { &*stack_frame };
-          let return_addr = stack_frame_ref.return_addr as u64;
-
-          if return_addr == 0 {
-            break;
-          }
-
-          stack_frame = stack_frame_ref.previous;
-
-          for entry in symbol_table {
-            let start = entry.value();
-            let end = start + entry.size();
-
-            if (start..=end).contains(&return_addr) {
-              let mangled_name = entry.get_name(&kernel_file).expect("could not get symbol name");
-              let demangled_name = rustc_demangle::demangle(mangled_name);
-
-              log::error!("{:#x}: {}", return_addr, demangled_name);
-            }
-          }
+    let kernel_file = symbols::kernel_file();
+
+    match ReturnAddresses::current() {
+      Some(return_addrs) => {
+        for return_addr in return_addrs.take(64) {
+          symbols::for_each_symbol(&kernel_file, return_addr, |name| {
+            log::error!("{:#x}: {}", return_addr, name);
+          });
         }
       }
-    } else {
-      panic!("symbol section data does not contain the symbol table");
+      None => log::error!("frame pointers were not emitted for this build, cannot print backtrace"),
     }
   }
 
@@ -0,0 +1,133 @@
+use super::lockdep;
+use crate::cpu::percpu;
+
+use core::{
+  fmt,
+  panic::Location,
+  ptr,
+  sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+// Roughly a second of spinning on current hardware before a contended lock gets reported.
+const SPIN_REPORT_CYCLES: u64 = 1 << 31;
+const NO_OWNER: usize = usize::MAX;
+
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+pub struct Site(pub *mut Location<'static>);
+
+impl fmt::Display for Site {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match unsafe { self.0.as_ref() } {
+      Some(location) => fmt::Display::fmt(location, f),
+      None => f.write_str("<unknown>"),
+    }
+  }
+}
+
+pub struct LockDebug {
+  owner_cpu: AtomicUsize,
+  owner_location: AtomicPtr<Location<'static>>,
+}
+
+fn current_cpu() -> usize {
+  percpu::try_current().map_or(0, |cpu| cpu.index)
+}
+
+// Runs `f` unless a report is already being written, as logging takes locks of its own.
+pub fn report<F: FnOnce()>(f: F) {
+  if !REPORTING.swap(true, Ordering::AcqRel) {
+    f();
+
+    REPORTING.store(false, Ordering::Release);
+  }
+}
+
+impl LockDebug {
+  pub const fn new() -> Self {
+    Self {
+      owner_cpu: AtomicUsize::new(NO_OWNER),
+      owner_location: AtomicPtr::new(ptr::null_mut()),
+    }
+  }
+
+  fn id(&self) -> usize {
+    self as *const Self as usize
+  }
+
+  fn owner_location(&self) -> Site {
+    Site(self.owner_location.load(Ordering::Acquire))
+  }
+
+  // `locked` tells whether the lock is already held by anyone, `poll` takes it if it is free by now.
+  fn wait<G, F: FnMut() -> Option<G>>(&self, cpu: usize, locked: bool, location: &'static Location<'static>, mut poll: F) -> G {
+    if locked && self.owner_cpu.load(Ordering::Acquire) == cpu {
+      panic!(
+        "deadlock: cpu {} tried to acquire lock {:#x} at {}, but already holds it since {}",
+        cpu,
+        self.id(),
+        location,
+        self.owner_location()
+      );
+    }
+
+    if !REPORTING.load(Ordering::Acquire) {
+      lockdep::before_acquire(cpu, self.id(), location);
+    }
+
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    let mut reported = false;
+
+    let guard = loop {
+      if let Some(guard) = poll() {
+        break guard;
+      }
+
+      if !reported && unsafe { core::arch::x86_64::_rdtsc() } - start > SPIN_REPORT_CYCLES {
+        reported = true;
+
+        report(|| {
+          log::warn!(
+            "cpu {} spinning on lock {:#x} at {}, held by cpu {} since {}",
+            cpu,
+            self.id(),
+            location,
+            self.owner_cpu.load(Ordering::Relaxed),
+            self.owner_location()
+          );
+        });
+      }
+
+      core::hint::spin_loop();
+    };
+
+    lockdep::acquired(cpu, self.id(), location);
+
+    guard
+  }
+
+  pub fn acquire<G, F: FnMut() -> Option<G>>(&self, locked: bool, location: &'static Location<'static>, poll: F) -> G {
+    let cpu = current_cpu();
+    let guard = self.wait(cpu, locked, location, poll);
+
+    self.owner_cpu.store(cpu, Ordering::Release);
+    self.owner_location.store(location as *const _ as *mut _, Ordering::Release);
+
+    guard
+  }
+
+  pub fn acquire_shared<G, F: FnMut() -> Option<G>>(&self, write_locked: bool, location: &'static Location<'static>, poll: F) -> G {
+    self.wait(current_cpu(), write_locked, location, poll)
+  }
+
+  pub fn release(&self) {
+    lockdep::released(current_cpu(), self.id());
+
+    self.owner_cpu.store(NO_OWNER, Ordering::Release);
+    self.owner_location.store(ptr::null_mut(), Ordering::Release);
+  }
+
+  pub fn release_shared(&self) {
+    lockdep::released(current_cpu(), self.id());
+  }
+}
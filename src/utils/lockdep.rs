@@ -0,0 +1,136 @@
+use super::lock_debug::{report, Site};
+use crate::cpu::percpu::MAX_CPUS;
+
+use core::{
+  panic::Location,
+  ptr,
+  sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+const MAX_CLASSES: usize = 64;
+const MAX_HELD: usize = 16;
+
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+const NO_EDGES: AtomicU64 = AtomicU64::new(0);
+const NO_SITE: AtomicPtr<Location<'static>> = AtomicPtr::new(ptr::null_mut());
+const NO_SITES: [AtomicPtr<Location<'static>>; MAX_CLASSES] = [NO_SITE; MAX_CLASSES];
+const NO_HELD: [AtomicUsize; MAX_HELD] = [ZERO; MAX_HELD];
+const NO_HELD_SITES: [AtomicPtr<Location<'static>>; MAX_HELD] = [NO_SITE; MAX_HELD];
+
+// Every lock is its own class, keyed by address.
+static CLASSES: [AtomicUsize; MAX_CLASSES] = [ZERO; MAX_CLASSES];
+
+// Bit `b` of `ORDER[a]` is set once lock `b` was taken while holding lock `a`.
+static ORDER: [AtomicU64; MAX_CLASSES] = [NO_EDGES; MAX_CLASSES];
+static REPORTED: [AtomicU64; MAX_CLASSES] = [NO_EDGES; MAX_CLASSES];
+static ORDER_SITES: [[AtomicPtr<Location<'static>>; MAX_CLASSES]; MAX_CLASSES] = [NO_SITES; MAX_CLASSES];
+
+static HELD: [[AtomicUsize; MAX_HELD]; MAX_CPUS] = [NO_HELD; MAX_CPUS];
+static HELD_SITES: [[AtomicPtr<Location<'static>>; MAX_HELD]; MAX_CPUS] = [NO_HELD_SITES; MAX_CPUS];
+static HELD_DEPTH: [AtomicUsize; MAX_CPUS] = [ZERO; MAX_CPUS];
+// Locks taken while `HELD` was full, those are not recorded and take no part in the order checks.
+static HELD_OVERFLOW: [AtomicUsize; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+fn class_of(lock: usize) -> Option<usize> {
+  for (class, slot) in CLASSES.iter().enumerate() {
+    match slot.compare_exchange(0, lock, Ordering::AcqRel, Ordering::Acquire) {
+      Ok(_) => return Some(class),
+      Err(existing) if existing == lock => return Some(class),
+      Err(_) => continue,
+    }
+  }
+
+  None
+}
+
+fn site(location: &'static Location<'static>) -> *mut Location<'static> {
+  location as *const _ as *mut _
+}
+
+pub fn before_acquire(cpu: usize, lock: usize, location: &'static Location<'static>) {
+  let class = match class_of(lock) {
+    Some(class) => class,
+    None => return,
+  };
+
+  let depth = HELD_DEPTH[cpu].load(Ordering::Relaxed);
+
+  for slot in 0..depth {
+    let held = HELD[cpu][slot].load(Ordering::Relaxed);
+
+    if held == 0 || held - 1 == class {
+      continue;
+    }
+
+    let held = held - 1;
+    let held_site = Site(HELD_SITES[cpu][slot].load(Ordering::Relaxed));
+
+    if ORDER[class].load(Ordering::Acquire) & (1_u64 << held) != 0 {
+      if REPORTED[class].fetch_or(1_u64 << held, Ordering::AcqRel) & (1_u64 << held) == 0 {
+        let inverse_site = Site(ORDER_SITES[class][held].load(Ordering::Relaxed));
+
+        report(|| {
+          log::error!(
+            "lock order inversion: acquiring lock {:#x} at {} while holding lock {:#x} taken at {}",
+            lock,
+            location,
+            CLASSES[held].load(Ordering::Relaxed),
+            held_site,
+          );
+          log::error!("the opposite order was established at {}", inverse_site);
+        });
+      }
+    } else if ORDER[held].fetch_or(1_u64 << class, Ordering::AcqRel) & (1_u64 << class) == 0 {
+      ORDER_SITES[held][class].store(site(location), Ordering::Relaxed);
+    }
+  }
+}
+
+pub fn acquired(cpu: usize, lock: usize, location: &'static Location<'static>) {
+  let class = match class_of(lock) {
+    Some(class) => class,
+    None => return,
+  };
+
+  let depth = HELD_DEPTH[cpu].load(Ordering::Relaxed);
+
+  if depth == MAX_HELD {
+    HELD_OVERFLOW[cpu].fetch_add(1, Ordering::Relaxed);
+
+    return;
+  }
+
+  HELD[cpu][depth].store(class + 1, Ordering::Relaxed);
+  HELD_SITES[cpu][depth].store(site(location), Ordering::Relaxed);
+  HELD_DEPTH[cpu].store(depth + 1, Ordering::Relaxed);
+}
+
+pub fn released(cpu: usize, lock: usize) {
+  let class = match class_of(lock) {
+    Some(class) => class,
+    None => return,
+  };
+
+  let depth = HELD_DEPTH[cpu].load(Ordering::Relaxed);
+
+  // Guards are usually dropped in reverse order, but nothing forces them to be.
+  let slot = match (0..depth).rev().find(|&slot| HELD[cpu][slot].load(Ordering::Relaxed) == class + 1) {
+    Some(slot) => slot,
+    None => {
+      let overflow = HELD_OVERFLOW[cpu].load(Ordering::Relaxed);
+
+      HELD_OVERFLOW[cpu].store(overflow.saturating_sub(1), Ordering::Relaxed);
+
+      return;
+    }
+  };
+
+  for next in slot..depth - 1 {
+    HELD[cpu][next].store(HELD[cpu][next + 1].load(Ordering::Relaxed), Ordering::Relaxed);
+    HELD_SITES[cpu][next].store(HELD_SITES[cpu][next + 1].load(Ordering::Relaxed), Ordering::Relaxed);
+  }
+
+  HELD[cpu][depth - 1].store(0, Ordering::Relaxed);
+  HELD_SITES[cpu][depth - 1].store(ptr::null_mut(), Ordering::Relaxed);
+  HELD_DEPTH[cpu].store(depth - 1, Ordering::Relaxed);
+}
@@ -1,17 +1,201 @@
+#[cfg(feature = "lock-debug")]
+use super::lock_debug::LockDebug;
+use crate::cpu::percpu;
+
+use core::{
+  mem::ManuallyDrop,
+  ops::{Deref, DerefMut},
+  panic::Location,
+  sync::atomic::{AtomicUsize, Ordering},
+};
 use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
 
 pub struct Locked<T> {
   inner: Mutex<T>,
+  #[cfg(feature = "lock-debug")]
+  debug: LockDebug,
+}
+
+pub struct LockedGuard<'a, T> {
+  guard: ManuallyDrop<MutexGuard<'a, T>>,
+  #[cfg(feature = "lock-debug")]
+  debug: &'a LockDebug,
+}
+
+#[cfg(feature = "lock-debug")]
+fn acquire<'a, T>(inner: &'a Mutex<T>, debug: &'a LockDebug, location: &'static Location<'static>) -> MutexGuard<'a, T> {
+  debug.acquire(inner.is_locked(), location, || inner.try_lock())
+}
+
+#[cfg(not(feature = "lock-debug"))]
+fn acquire<T>(inner: &Mutex<T>, _: &'static Location<'static>) -> MutexGuard<T> {
+  inner.lock()
 }
 
 impl<T> Locked<T> {
   pub const fn new(inner: T) -> Self {
     Locked {
       inner: spin::Mutex::new(inner),
+      #[cfg(feature = "lock-debug")]
+      debug: LockDebug::new(),
+    }
+  }
+
+  #[track_caller]
+  pub fn lock(&self) -> LockedGuard<T> {
+    let location = Location::caller();
+
+    LockedGuard {
+      #[cfg(feature = "lock-debug")]
+      guard: ManuallyDrop::new(acquire(&self.inner, &self.debug, location)),
+      #[cfg(not(feature = "lock-debug"))]
+      guard: ManuallyDrop::new(acquire(&self.inner, location)),
+      #[cfg(feature = "lock-debug")]
+      debug: &self.debug,
+    }
+  }
+}
+
+impl<'a, T> Deref for LockedGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.guard
+  }
+}
+
+impl<'a, T> DerefMut for LockedGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.guard
+  }
+}
+
+impl<'a, T> Drop for LockedGuard<'a, T> {
+  fn drop(&mut self) {
+    #[cfg(feature = "lock-debug")]
+    self.debug.release();
+
+    unsafe { ManuallyDrop::drop(&mut self.guard) }
+  }
+}
+
+pub struct SavedInterrupts {
+  enabled: bool,
+}
+
+impl SavedInterrupts {
+  pub fn save_and_disable() -> Self {
+    let enabled = interrupts::are_enabled();
+
+    interrupts::disable();
+
+    Self { enabled }
+  }
+
+  pub fn restore(&self) {
+    if self.enabled {
+      interrupts::enable();
     }
   }
+}
+
+const NO_OWNER: usize = 0;
+
+pub struct Owner(AtomicUsize);
+
+impl Owner {
+  pub const fn new() -> Self {
+    Owner(AtomicUsize::new(NO_OWNER))
+  }
+
+  // Before per-cpu data exists only the boot cpu runs.
+  fn current_cpu() -> usize {
+    percpu::try_current().map_or(usize::MAX, |cpu| cpu.index + 1)
+  }
+
+  pub fn set(&self) {
+    self.0.store(Self::current_cpu(), Ordering::Relaxed);
+  }
+
+  pub fn clear(&self) {
+    self.0.store(NO_OWNER, Ordering::Relaxed);
+  }
+
+  pub fn is_current_cpu(&self) -> bool {
+    self.0.load(Ordering::Relaxed) == Self::current_cpu()
+  }
+}
+
+pub struct IrqLocked<T> {
+  inner: Locked<T>,
+  owner: Owner,
+}
+
+pub struct IrqLockedGuard<'a, T> {
+  guard: ManuallyDrop<LockedGuard<'a, T>>,
+  owner: &'a Owner,
+  interrupts: SavedInterrupts,
+}
+
+impl<T> IrqLocked<T> {
+  pub const fn new(inner: T) -> Self {
+    IrqLocked {
+      inner: Locked::new(inner),
+      owner: Owner::new(),
+    }
+  }
+
+  #[track_caller]
+  pub fn lock(&self) -> IrqLockedGuard<T> {
+    let interrupts = SavedInterrupts::save_and_disable();
+    let guard = ManuallyDrop::new(self.inner.lock());
+
+    self.owner.set();
+
+    IrqLockedGuard {
+      guard,
+      owner: &self.owner,
+      interrupts,
+    }
+  }
+
+  pub fn is_held_by_current_cpu(&self) -> bool {
+    self.owner.is_current_cpu()
+  }
+
+  // Only meant for the panic path, where whoever held the lock is never going to release it.
+  pub unsafe fn force_unlock(&self) {
+    if self.inner.inner.is_locked() {
+      #[cfg(feature = "lock-debug")]
+      self.inner.debug.release();
+
+      self.owner.clear();
+      self.inner.inner.force_unlock();
+    }
+  }
+}
+
+impl<'a, T> Deref for IrqLockedGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.guard
+  }
+}
+
+impl<'a, T> DerefMut for IrqLockedGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.guard
+  }
+}
+
+impl<'a, T> Drop for IrqLockedGuard<'a, T> {
+  fn drop(&mut self) {
+    self.owner.clear();
+
+    unsafe { ManuallyDrop::drop(&mut self.guard) }
 
-  pub fn lock(&self) -> MutexGuard<T> {
-    self.inner.lock()
+    self.interrupts.restore();
   }
 }
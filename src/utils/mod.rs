@@ -1 +1,9 @@
+#[cfg(feature = "lock-debug")]
+mod lock_debug;
+#[cfg(feature = "lock-debug")]
+mod lockdep;
 pub mod locked;
+pub mod rwlock;
+pub mod seqlock;
+pub mod symbols;
+pub mod ticket;
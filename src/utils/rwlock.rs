@@ -0,0 +1,138 @@
+#[cfg(feature = "lock-debug")]
+use super::lock_debug::LockDebug;
+use super::locked::SavedInterrupts;
+
+use core::{
+  mem::ManuallyDrop,
+  ops::{Deref, DerefMut},
+  panic::Location,
+};
+use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+pub struct IrqRwLocked<T> {
+  inner: RwLock<T>,
+  #[cfg(feature = "lock-debug")]
+  debug: LockDebug,
+}
+
+pub struct IrqRwLockedReadGuard<'a, T> {
+  guard: ManuallyDrop<RwLockReadGuard<'a, T>>,
+  #[cfg(feature = "lock-debug")]
+  debug: &'a LockDebug,
+  interrupts: SavedInterrupts,
+}
+
+pub struct IrqRwLockedWriteGuard<'a, T> {
+  guard: ManuallyDrop<RwLockWriteGuard<'a, T>>,
+  #[cfg(feature = "lock-debug")]
+  debug: &'a LockDebug,
+  interrupts: SavedInterrupts,
+}
+
+#[cfg(feature = "lock-debug")]
+fn acquire_read<'a, T>(inner: &'a RwLock<T>, debug: &'a LockDebug, location: &'static Location<'static>) -> RwLockReadGuard<'a, T> {
+  debug.acquire_shared(inner.writer_count() > 0, location, || inner.try_read())
+}
+
+#[cfg(not(feature = "lock-debug"))]
+fn acquire_read<T>(inner: &RwLock<T>, _: &'static Location<'static>) -> RwLockReadGuard<T> {
+  inner.read()
+}
+
+#[cfg(feature = "lock-debug")]
+fn acquire_write<'a, T>(inner: &'a RwLock<T>, debug: &'a LockDebug, location: &'static Location<'static>) -> RwLockWriteGuard<'a, T> {
+  let locked = inner.writer_count() > 0 || inner.reader_count() > 0;
+
+  debug.acquire(locked, location, || inner.try_write())
+}
+
+#[cfg(not(feature = "lock-debug"))]
+fn acquire_write<T>(inner: &RwLock<T>, _: &'static Location<'static>) -> RwLockWriteGuard<T> {
+  inner.write()
+}
+
+impl<T> IrqRwLocked<T> {
+  pub const fn new(inner: T) -> Self {
+    IrqRwLocked {
+      inner: RwLock::new(inner),
+      #[cfg(feature = "lock-debug")]
+      debug: LockDebug::new(),
+    }
+  }
+
+  #[track_caller]
+  pub fn read(&self) -> IrqRwLockedReadGuard<T> {
+    let location = Location::caller();
+    let interrupts = SavedInterrupts::save_and_disable();
+
+    IrqRwLockedReadGuard {
+      #[cfg(feature = "lock-debug")]
+      guard: ManuallyDrop::new(acquire_read(&self.inner, &self.debug, location)),
+      #[cfg(not(feature = "lock-debug"))]
+      guard: ManuallyDrop::new(acquire_read(&self.inner, location)),
+      #[cfg(feature = "lock-debug")]
+      debug: &self.debug,
+      interrupts,
+    }
+  }
+
+  #[track_caller]
+  pub fn write(&self) -> IrqRwLockedWriteGuard<T> {
+    let location = Location::caller();
+    let interrupts = SavedInterrupts::save_and_disable();
+
+    IrqRwLockedWriteGuard {
+      #[cfg(feature = "lock-debug")]
+      guard: ManuallyDrop::new(acquire_write(&self.inner, &self.debug, location)),
+      #[cfg(not(feature = "lock-debug"))]
+      guard: ManuallyDrop::new(acquire_write(&self.inner, location)),
+      #[cfg(feature = "lock-debug")]
+      debug: &self.debug,
+      interrupts,
+    }
+  }
+}
+
+impl<'a, T> Deref for IrqRwLockedReadGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.guard
+  }
+}
+
+impl<'a, T> Drop for IrqRwLockedReadGuard<'a, T> {
+  fn drop(&mut self) {
+    #[cfg(feature = "lock-debug")]
+    self.debug.release_shared();
+
+    unsafe { ManuallyDrop::drop(&mut self.guard) }
+
+    self.interrupts.restore();
+  }
+}
+
+impl<'a, T> Deref for IrqRwLockedWriteGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.guard
+  }
+}
+
+impl<'a, T> DerefMut for IrqRwLockedWriteGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.guard
+  }
+}
+
+impl<'a, T> Drop for IrqRwLockedWriteGuard<'a, T> {
+  fn drop(&mut self) {
+    #[cfg(feature = "lock-debug")]
+    self.debug.release();
+
+    unsafe { ManuallyDrop::drop(&mut self.guard) }
+
+    self.interrupts.restore();
+  }
+}
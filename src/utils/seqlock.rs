@@ -0,0 +1,118 @@
+#[cfg(feature = "lock-debug")]
+use super::lock_debug::LockDebug;
+use super::locked::SavedInterrupts;
+
+use core::{
+  cell::UnsafeCell,
+  mem::ManuallyDrop,
+  ops::{Deref, DerefMut},
+  panic::Location,
+  ptr,
+  sync::atomic::{self, AtomicUsize, Ordering},
+};
+use spin::{Mutex, MutexGuard};
+
+pub struct SeqLocked<T> {
+  sequence: AtomicUsize,
+  writer: Mutex<()>,
+  inner: UnsafeCell<T>,
+  #[cfg(feature = "lock-debug")]
+  debug: LockDebug,
+}
+
+unsafe impl<T: Copy + Send> Send for SeqLocked<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLocked<T> {}
+
+pub struct SeqLockedWriteGuard<'a, T: Copy> {
+  lock: &'a SeqLocked<T>,
+  writer: ManuallyDrop<MutexGuard<'a, ()>>,
+  interrupts: SavedInterrupts,
+}
+
+impl<T> SeqLocked<T> {
+  pub const fn new(inner: T) -> Self {
+    SeqLocked {
+      sequence: AtomicUsize::new(0),
+      writer: Mutex::new(()),
+      inner: UnsafeCell::new(inner),
+      #[cfg(feature = "lock-debug")]
+      debug: LockDebug::new(),
+    }
+  }
+
+  // Only writers take a lock, readers never show up in the lock-debug checks.
+  #[cfg(feature = "lock-debug")]
+  fn lock_writer(&self, location: &'static Location<'static>) -> MutexGuard<()> {
+    self.debug.acquire(self.writer.is_locked(), location, || self.writer.try_lock())
+  }
+
+  #[cfg(not(feature = "lock-debug"))]
+  fn lock_writer(&self, _: &'static Location<'static>) -> MutexGuard<()> {
+    self.writer.lock()
+  }
+}
+
+impl<T: Copy> SeqLocked<T> {
+  pub fn read(&self) -> T {
+    loop {
+      let sequence = self.sequence.load(Ordering::Acquire);
+
+      if sequence & 1 != 0 {
+        core::hint::spin_loop();
+        continue;
+      }
+
+      let value = unsafe { ptr::read_volatile(self.inner.get()) };
+
+      atomic::fence(Ordering::Acquire);
+
+      if self.sequence.load(Ordering::Relaxed) == sequence {
+        return value;
+      }
+    }
+  }
+
+  // Interrupts stay disabled while writing, a reader in a handler would never see the write complete.
+  #[track_caller]
+  pub fn write(&self) -> SeqLockedWriteGuard<T> {
+    let location = Location::caller();
+    let interrupts = SavedInterrupts::save_and_disable();
+    let writer = self.lock_writer(location);
+
+    self.sequence.fetch_add(1, Ordering::Relaxed);
+    atomic::fence(Ordering::Release);
+
+    SeqLockedWriteGuard {
+      lock: self,
+      writer: ManuallyDrop::new(writer),
+      interrupts,
+    }
+  }
+}
+
+impl<'a, T: Copy> Deref for SeqLockedWriteGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.inner.get() }
+  }
+}
+
+impl<'a, T: Copy> DerefMut for SeqLockedWriteGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.lock.inner.get() }
+  }
+}
+
+impl<'a, T: Copy> Drop for SeqLockedWriteGuard<'a, T> {
+  fn drop(&mut self) {
+    self.lock.sequence.fetch_add(1, Ordering::Release);
+
+    #[cfg(feature = "lock-debug")]
+    self.lock.debug.release();
+
+    unsafe { ManuallyDrop::drop(&mut self.writer) }
+
+    self.interrupts.restore();
+  }
+}
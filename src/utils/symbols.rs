@@ -0,0 +1,118 @@
+use crate::{memory::stack, KERNEL_INFO, PHYS_MEM_OFFSET};
+
+use core::mem::size_of;
+use rustc_demangle::Demangle;
+use x86_64::VirtAddr;
+use xmas_elf::{
+  sections::{SectionData, ShType},
+  symbol_table::Entry,
+  ElfFile,
+};
+
+#[repr(C)]
+struct StackFrame {
+  previous: *const StackFrame,
+  return_addr: usize,
+}
+
+// Stops at frames that leave the kernel stack the walk started on.
+pub struct ReturnAddresses {
+  frame: *const StackFrame,
+  checked: bool,
+}
+
+fn on_kernel_stack(frame: *const StackFrame) -> bool {
+  let addr = frame as u64;
+
+  match stack::bounds(VirtAddr::new_truncate(addr)) {
+    Some((_, top)) => addr + size_of::<StackFrame>() as u64 <= top.as_u64(),
+    None => false,
+  }
+}
+
+impl ReturnAddresses {
+  // `None` if frame pointers were not emitted for this build.
+  #[inline(always)]
+  pub fn current() -> Option<Self> {
+    let frame: *const StackFrame;
+
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) }
+
+    if frame.is_null() {
+      None
+    } else {
+      Some(Self {
+        frame,
+        checked: on_kernel_stack(frame),
+      })
+    }
+  }
+}
+
+impl Iterator for ReturnAddresses {
+  type Item = u64;
+
+  fn next(&mut self) -> Option<u64> {
+    if self.frame.is_null() || (self.checked && !on_kernel_stack(self.frame)) {
+      return None;
+    }
+
+    let frame = unsafe { &*self.frame };
+    let return_addr = frame.return_addr as u64;
+
+    if return_addr == 0 {
+      return None;
+    }
+
+    self.frame = frame.previous;
+
+    Some(return_addr)
+  }
+}
+
+pub fn kernel_file() -> ElfFile<'static> {
+  let kernel_info = KERNEL_INFO.get().expect("no kernel info was located");
+  let phys_memory_offset = PHYS_MEM_OFFSET.get().expect("how did we get here?").as_u64();
+
+  let kernel_data = unsafe {
+    core::slice::from_raw_parts(
+      (kernel_info.kernel_base + phys_memory_offset) as *const u8,
+      kernel_info.kernel_size as usize,
+    )
+  };
+
+  ElfFile::new(kernel_data).expect("could not read kernel binary")
+}
+
+pub fn for_each_symbol<F: FnMut(Demangle<'static>)>(kernel_file: &ElfFile<'static>, addr: u64, mut f: F) {
+  let symbols_data = kernel_file
+    .section_iter()
+    .find(|sect| sect.get_type() == Ok(ShType::SymTab))
+    .map(|sect| sect.get_data(kernel_file))
+    .unwrap();
+
+  if let SectionData::SymbolTable64(symbol_table) = symbols_data.unwrap() {
+    for entry in symbol_table {
+      let start = entry.value();
+      let end = start + entry.size();
+
+      if (start..=end).contains(&addr) {
+        let mangled_name = entry.get_name(kernel_file).expect("could not get symbol name");
+
+        f(rustc_demangle::demangle(mangled_name));
+      }
+    }
+  } else {
+    panic!("symbol section data does not contain the symbol table");
+  }
+}
+
+pub fn lookup(addr: u64) -> Option<Demangle<'static>> {
+  let mut symbol = None;
+
+  for_each_symbol(&kernel_file(), addr, |name| {
+    symbol.get_or_insert(name);
+  });
+
+  symbol
+}
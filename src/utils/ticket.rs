@@ -0,0 +1,161 @@
+#[cfg(feature = "lock-debug")]
+use super::lock_debug::LockDebug;
+use super::locked::{Owner, SavedInterrupts};
+
+use core::{
+  cell::UnsafeCell,
+  mem::ManuallyDrop,
+  ops::{Deref, DerefMut},
+  panic::Location,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+// FIFO spinlock: cpus are served in the order they started waiting, so none of them can starve.
+pub struct TicketLocked<T> {
+  next_ticket: AtomicUsize,
+  now_serving: AtomicUsize,
+  inner: UnsafeCell<T>,
+  #[cfg(feature = "lock-debug")]
+  debug: LockDebug,
+}
+
+unsafe impl<T: Send> Send for TicketLocked<T> {}
+unsafe impl<T: Send> Sync for TicketLocked<T> {}
+
+pub struct TicketLockedGuard<'a, T> {
+  lock: &'a TicketLocked<T>,
+}
+
+// The ticket is only drawn once the lock-debug checks passed, a cpu about to deadlock on itself must not queue up.
+#[cfg(feature = "lock-debug")]
+fn acquire<T>(lock: &TicketLocked<T>, location: &'static Location<'static>) {
+  let locked = lock.next_ticket.load(Ordering::Relaxed) != lock.now_serving.load(Ordering::Relaxed);
+  let mut ticket = None;
+
+  lock.debug.acquire(locked, location, || {
+    let ticket = *ticket.get_or_insert_with(|| lock.take_ticket());
+
+    lock.is_serving(ticket).then(|| ())
+  })
+}
+
+#[cfg(not(feature = "lock-debug"))]
+fn acquire<T>(lock: &TicketLocked<T>, _: &'static Location<'static>) {
+  let ticket = lock.take_ticket();
+
+  while !lock.is_serving(ticket) {
+    core::hint::spin_loop();
+  }
+}
+
+impl<T> TicketLocked<T> {
+  pub const fn new(inner: T) -> Self {
+    TicketLocked {
+      next_ticket: AtomicUsize::new(0),
+      now_serving: AtomicUsize::new(0),
+      inner: UnsafeCell::new(inner),
+      #[cfg(feature = "lock-debug")]
+      debug: LockDebug::new(),
+    }
+  }
+
+  fn take_ticket(&self) -> usize {
+    self.next_ticket.fetch_add(1, Ordering::Relaxed)
+  }
+
+  fn is_serving(&self, ticket: usize) -> bool {
+    self.now_serving.load(Ordering::Acquire) == ticket
+  }
+
+  #[track_caller]
+  pub fn lock(&self) -> TicketLockedGuard<T> {
+    acquire(self, Location::caller());
+
+    TicketLockedGuard { lock: self }
+  }
+}
+
+impl<'a, T> Deref for TicketLockedGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.inner.get() }
+  }
+}
+
+impl<'a, T> DerefMut for TicketLockedGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.lock.inner.get() }
+  }
+}
+
+impl<'a, T> Drop for TicketLockedGuard<'a, T> {
+  fn drop(&mut self) {
+    #[cfg(feature = "lock-debug")]
+    self.lock.debug.release();
+
+    self.lock.now_serving.fetch_add(1, Ordering::Release);
+  }
+}
+
+pub struct IrqTicketLocked<T> {
+  inner: TicketLocked<T>,
+  owner: Owner,
+}
+
+pub struct IrqTicketLockedGuard<'a, T> {
+  guard: ManuallyDrop<TicketLockedGuard<'a, T>>,
+  owner: &'a Owner,
+  interrupts: SavedInterrupts,
+}
+
+impl<T> IrqTicketLocked<T> {
+  pub const fn new(inner: T) -> Self {
+    IrqTicketLocked {
+      inner: TicketLocked::new(inner),
+      owner: Owner::new(),
+    }
+  }
+
+  #[track_caller]
+  pub fn lock(&self) -> IrqTicketLockedGuard<T> {
+    let interrupts = SavedInterrupts::save_and_disable();
+    let guard = ManuallyDrop::new(self.inner.lock());
+
+    self.owner.set();
+
+    IrqTicketLockedGuard {
+      guard,
+      owner: &self.owner,
+      interrupts,
+    }
+  }
+
+  pub fn is_held_by_current_cpu(&self) -> bool {
+    self.owner.is_current_cpu()
+  }
+}
+
+impl<'a, T> Deref for IrqTicketLockedGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.guard
+  }
+}
+
+impl<'a, T> DerefMut for IrqTicketLockedGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.guard
+  }
+}
+
+impl<'a, T> Drop for IrqTicketLockedGuard<'a, T> {
+  fn drop(&mut self) {
+    self.owner.clear();
+
+    unsafe { ManuallyDrop::drop(&mut self.guard) }
+
+    self.interrupts.restore();
+  }
+}